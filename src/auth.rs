@@ -1,47 +1,492 @@
-use crate::SQLxSessionAuth;
+use crate::{CachedPermissions, SQLxAuth, SQLxSessionAuth, SqlxDatabase};
 use async_recursion::async_recursion;
-use rocket::http::Method;
+use chrono::{DateTime, Utc};
+use rocket::http::{Method, Status};
 use sqlx::pool::PoolConnection;
-use std::marker::PhantomData;
+use std::{
+    collections::{BTreeSet, HashMap},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+/// The namespace bare Rights::Permission leaves belong to.
+pub const DEFAULT_NAMESPACE: &str = "default";
 
 #[rocket::async_trait]
 pub trait HasPermission {
-    async fn has(&self, perm: &str, pool: &Option<&mut PoolConnection<sqlx::Postgres>>) -> bool;
+    async fn has(&self, perm: &str, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool;
+
+    /// Checks if the permission was held at as_of, None meaning now. Override this if permission
+    /// grants have validity ranges, by default the time is ignored and has is used.
+    async fn has_as_of(
+        &self,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        let _ = as_of;
+        self.has(perm, pool).await
+    }
+
+    /// Checks a permission within a namespace, like feature flags kept apart from RBAC permissions.
+    /// By default the DEFAULT_NAMESPACE uses has and every other namespace is denied.
+    async fn has_in(
+        &self,
+        namespace: &str,
+        perm: &str,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        if namespace == DEFAULT_NAMESPACE {
+            self.has(perm, pool).await
+        } else {
+            false
+        }
+    }
+
+    /// Checks a permission within a namespace as it was at as_of, None meaning now. By default
+    /// the DEFAULT_NAMESPACE uses has_as_of while other namespaces ignore the time and use has_in,
+    /// override it if namespaced grants have validity ranges.
+    async fn has_in_as_of(
+        &self,
+        namespace: &str,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        match as_of {
+            Some(_) if namespace == DEFAULT_NAMESPACE => self.has_as_of(perm, as_of, pool).await,
+            _ => self.has_in(namespace, perm, pool).await,
+        }
+    }
+
+    /// Checks several permissions at once returning a result per permission in the same order.
+    /// Rights uses this for the plain Permission children of All and Any groups when batches
+    /// returns true. Override it to resolve them in a single query, the default calls has for each.
+    async fn has_all(
+        &self,
+        perms: &[&str],
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> Vec<bool> {
+        let mut results = Vec::with_capacity(perms.len());
+
+        for perm in perms {
+            results.push(self.has(perm, pool).await);
+        }
+
+        results
+    }
+
+    /// If Rights should prefetch the Permission children of a group with has_all. Return true
+    /// when has_all is a single query, otherwise groups stop at the first deciding child.
+    fn batches(&self) -> bool {
+        false
+    }
+
+    /// Lists every permission the user holds. Only used by tooling like permission_diff,
+    /// the default returns no permissions.
+    async fn permissions(&self, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> Vec<String> {
+        let _ = pool;
+        Vec::new()
+    }
+
+    /// Return Some(self) when the user also implements HasQuota so Rights::Quota can be checked.
+    /// Quotas always fail for users that return None.
+    fn as_quota(&self) -> Option<&(dyn HasQuota + Sync)> {
+        None
+    }
+
+    /// Return Some(self) when the user also implements HasRoles so roles can be previewed.
+    /// Previews deny every permission for users that return None.
+    fn as_roles(&self) -> Option<&(dyn HasRoles + Sync)> {
+        None
+    }
+}
+
+/// Usage counts for Rights::Quota, like the amount of posts created today.
+#[rocket::async_trait]
+pub trait HasQuota {
+    async fn current(&self, name: &str, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> i64;
+}
+
+/// Looks up which permissions a role grants, used to preview the site as a role.
+#[rocket::async_trait]
+pub trait HasRoles {
+    async fn role_has(
+        &self,
+        role: &str,
+        perm: &str,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool;
+}
+
+/// Wraps a user so only the permissions of the previewed role are exposed. This is not
+/// impersonation, the user stays themselves and a permission must be granted by both the
+/// role and the user, so previewing can never grant more than the user already has.
+/// The role is looked up through HasPermission::as_roles.
+pub struct RolePreview<'a, D> {
+    user: &'a D,
+    role: String,
+}
+
+impl<'a, D> RolePreview<'a, D> {
+    pub fn new(user: &'a D, role: impl Into<String>) -> Self {
+        Self {
+            user,
+            role: role.into(),
+        }
+    }
+
+    pub fn role(&self) -> &str {
+        &self.role
+    }
+
+    pub fn user(&self) -> &D {
+        self.user
+    }
+}
+
+impl<'a, D> RolePreview<'a, D>
+where
+    D: HasPermission + Sync,
+{
+    async fn role_has(&self, perm: &str, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+        match self.user.as_roles() {
+            Some(roles) => roles.role_has(&self.role, perm, pool).await,
+            None => false,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, D> HasPermission for RolePreview<'a, D>
+where
+    D: HasPermission + Sync,
+{
+    async fn has(&self, perm: &str, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+        self.user.has(perm, pool).await && self.role_has(perm, pool).await
+    }
+
+    async fn has_as_of(
+        &self,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.user.has_as_of(perm, as_of, pool).await && self.role_has(perm, pool).await
+    }
+
+    async fn has_in(
+        &self,
+        namespace: &str,
+        perm: &str,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.user.has_in(namespace, perm, pool).await && self.role_has(perm, pool).await
+    }
+
+    async fn has_in_as_of(
+        &self,
+        namespace: &str,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.user.has_in_as_of(namespace, perm, as_of, pool).await
+            && self.role_has(perm, pool).await
+    }
+
+    async fn permissions(&self, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> Vec<String> {
+        let mut perms = Vec::new();
+
+        for perm in self.user.permissions(pool).await {
+            if self.role_has(&perm, pool).await {
+                perms.push(perm);
+            }
+        }
+
+        perms
+    }
+
+    fn as_quota(&self) -> Option<&(dyn HasQuota + Sync)> {
+        self.user.as_quota()
+    }
+
+    fn as_roles(&self) -> Option<&(dyn HasRoles + Sync)> {
+        self.user.as_roles()
+    }
+}
+
+/// The current user as the guards check their permissions, see SQLxAuth::permission_user.
+pub enum PermissionUser<'a, D> {
+    User(&'a D),
+    /// Lookups go through the fairings PermissionCache.
+    Cached(CachedPermissions<'a, D>),
+    /// A role is being previewed, previews are never cached.
+    Preview(RolePreview<'a, D>),
+}
+
+impl<'a, D> PermissionUser<'a, D>
+where
+    D: HasPermission + Sync,
+{
+    fn inner(&self) -> &(dyn HasPermission + Sync + '_) {
+        match self {
+            PermissionUser::User(user) => *user,
+            PermissionUser::Cached(cached) => cached,
+            PermissionUser::Preview(preview) => preview,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, D> HasPermission for PermissionUser<'a, D>
+where
+    D: HasPermission + Sync,
+{
+    async fn has(&self, perm: &str, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+        self.inner().has(perm, pool).await
+    }
+
+    async fn has_as_of(
+        &self,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.inner().has_as_of(perm, as_of, pool).await
+    }
+
+    async fn has_in(
+        &self,
+        namespace: &str,
+        perm: &str,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.inner().has_in(namespace, perm, pool).await
+    }
+
+    async fn has_in_as_of(
+        &self,
+        namespace: &str,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.inner().has_in_as_of(namespace, perm, as_of, pool).await
+    }
+
+    async fn has_all(
+        &self,
+        perms: &[&str],
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> Vec<bool> {
+        self.inner().has_all(perms, pool).await
+    }
+
+    fn batches(&self) -> bool {
+        self.inner().batches()
+    }
+
+    async fn permissions(&self, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> Vec<String> {
+        self.inner().permissions(pool).await
+    }
+
+    fn as_quota(&self) -> Option<&(dyn HasQuota + Sync)> {
+        self.inner().as_quota()
+    }
+
+    fn as_roles(&self) -> Option<&(dyn HasRoles + Sync)> {
+        self.inner().as_roles()
+    }
 }
 
+/// An ad hoc check within a Rights tree for what is not a permission, like the user owning
+/// a resource. These are usually built per request holding what they need to check.
+#[rocket::async_trait]
+pub trait CustomRight: Send + Sync {
+    async fn check(
+        &self,
+        user: &(dyn HasPermission + Sync),
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool;
+}
+
+/// A tree of permission checks. Permissions are Strings by default, any P: AsRef<str> like an
+/// enum of the apps permissions can be used instead so typos fail to compile. HasPermission is
+/// handed the permissions as_ref str.
 #[derive(Clone)]
-pub enum Rights {
-    All(Box<[Rights]>),
-    Any(Box<[Rights]>),
-    NoneOf(Box<[Rights]>),
-    Permission(String),
+pub enum Rights<P = String> {
+    All(Box<[Rights<P>]>),
+    Any(Box<[Rights<P>]>),
+    NoneOf(Box<[Rights<P>]>),
+    /// Passes when at least the given number of the Rights pass.
+    AtLeast(usize, Box<[Rights<P>]>),
+    /// Passes when exactly one of the Rights passes, an exclusive or.
+    ExactlyOne(Box<[Rights<P>]>),
+    Permission(P),
+    /// A permission checked with HasPermission::has_in, as (namespace, permission).
+    NamespacedPermission(String, P),
+    /// Passes while the users current usage of name is below limit, also under evaluate_as_of.
+    Quota { name: String, limit: i64 },
+    /// Passes when the CustomRight's check does.
+    Custom(Arc<dyn CustomRight>),
     None,
 }
 
-impl Rights {
-    pub fn all(data: &[Rights]) -> Rights {
+impl<P: Clone> Rights<P> {
+    pub fn all(data: &[Rights<P>]) -> Rights<P> {
         Rights::All(data.iter().cloned().collect())
     }
 
-    pub fn any(data: &[Rights]) -> Rights {
+    pub fn any(data: &[Rights<P>]) -> Rights<P> {
         Rights::Any(data.iter().cloned().collect())
     }
 
-    pub fn none(data: &[Rights]) -> Rights {
+    pub fn none(data: &[Rights<P>]) -> Rights<P> {
         Rights::NoneOf(data.iter().cloned().collect())
     }
 
-    #[async_recursion()]
+    pub fn at_least(n: usize, data: &[Rights<P>]) -> Rights<P> {
+        Rights::AtLeast(n, data.iter().cloned().collect())
+    }
+
+    pub fn exactly_one(data: &[Rights<P>]) -> Rights<P> {
+        Rights::ExactlyOne(data.iter().cloned().collect())
+    }
+
+    pub fn custom(right: impl CustomRight + 'static) -> Rights<P> {
+        Rights::Custom(Arc::new(right))
+    }
+}
+
+impl<P> Rights<P>
+where
+    P: AsRef<str> + Clone + Send + Sync,
+{
+    /// Repeated permissions within the tree are only looked up once per evaluate.
     pub async fn evaluate(
         &self,
         user: &(dyn HasPermission + Sync),
-        db: &Option<&mut PoolConnection<sqlx::Postgres>>,
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        self.evaluate_as_of(user, db, None).await
+    }
+
+    /// The distinct (namespace, permission) pairs within the tree, each is looked up at most once
+    /// by evaluate. Useful to prefetch them in a single query.
+    pub fn permissions(&self) -> BTreeSet<(String, String)> {
+        let mut perms = BTreeSet::new();
+        self.collect_permissions(&mut perms);
+        perms
+    }
+
+    fn collect_permissions(&self, perms: &mut BTreeSet<(String, String)>) {
+        match self {
+            Self::All(rights)
+            | Self::Any(rights)
+            | Self::NoneOf(rights)
+            | Self::AtLeast(_, rights)
+            | Self::ExactlyOne(rights) => {
+                for r in rights.iter() {
+                    r.collect_permissions(perms);
+                }
+            }
+            Self::Permission(perm) => {
+                perms.insert((DEFAULT_NAMESPACE.to_string(), perm.as_ref().to_string()));
+            }
+            Self::NamespacedPermission(namespace, perm) => {
+                perms.insert((namespace.clone(), perm.as_ref().to_string()));
+            }
+            Self::Quota { .. } | Self::Custom(_) | Self::None => {}
+        }
+    }
+
+    /// Evaluates the Rights as they were at as_of, None meaning now. See HasPermission::has_as_of
+    /// and has_in_as_of. Quota and Custom leaves always check the current state.
+    pub async fn evaluate_as_of(
+        &self,
+        user: &(dyn HasPermission + Sync),
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+        as_of: Option<DateTime<Utc>>,
+    ) -> bool {
+        let mut cache = HashMap::new();
+        self.evaluate_cached(user, db, as_of, &mut cache).await
+    }
+
+    /// Returns Status::Ok if the Rights pass otherwise Status::Forbidden.
+    pub async fn evaluate_to_status(
+        &self,
+        user: &(dyn HasPermission + Sync),
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> Status {
+        self.evaluate_to_status_or(user, db, Status::Forbidden).await
+    }
+
+    /// Returns Status::Ok if the Rights pass otherwise the denied Status.
+    pub async fn evaluate_to_status_or(
+        &self,
+        user: &(dyn HasPermission + Sync),
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+        denied: Status,
+    ) -> Status {
+        if self.evaluate(user, db).await {
+            Status::Ok
+        } else {
+            denied
+        }
+    }
+
+    /// Resolves the not yet cached Permission children with a single has_all. Skipped unless the
+    /// user batches, and when evaluating as of a time since has_all has no time.
+    async fn prefetch(
+        rights: &[Rights<P>],
+        user: &(dyn HasPermission + Sync),
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+        as_of: Option<DateTime<Utc>>,
+        cache: &mut HashMap<(String, String), bool>,
+    ) {
+        if as_of.is_some() || !user.batches() {
+            return;
+        }
+
+        let perms: BTreeSet<&str> = rights
+            .iter()
+            .filter_map(|right| match right {
+                Self::Permission(perm) => Some(perm.as_ref()),
+                _ => None,
+            })
+            .filter(|perm| !cache.contains_key(&(DEFAULT_NAMESPACE.to_string(), perm.to_string())))
+            .collect();
+
+        if perms.len() < 2 {
+            return;
+        }
+
+        let perms: Vec<&str> = perms.into_iter().collect();
+        let results = user.has_all(&perms, db).await;
+
+        for (perm, has) in perms.iter().zip(results) {
+            cache.insert((DEFAULT_NAMESPACE.to_string(), perm.to_string()), has);
+        }
+    }
+
+    /// Each distinct (namespace, permission) is only resolved once per evaluate, the results are
+    /// memoized in cache.
+    #[async_recursion()]
+    async fn evaluate_cached(
+        &self,
+        user: &(dyn HasPermission + Sync),
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+        as_of: Option<DateTime<Utc>>,
+        cache: &mut HashMap<(String, String), bool>,
     ) -> bool {
         match self {
             Self::All(rights) => {
+                Self::prefetch(rights, user, db, as_of, cache).await;
+
                 let mut all = true;
                 for r in rights.iter() {
-                    if !r.evaluate(user, db).await {
+                    if !r.evaluate_cached(user, db, as_of, cache).await {
                         all = false;
                         break;
                     }
@@ -50,9 +495,11 @@ impl Rights {
                 all
             }
             Self::Any(rights) => {
+                Self::prefetch(rights, user, db, as_of, cache).await;
+
                 let mut all = false;
                 for r in rights.iter() {
-                    if r.evaluate(user, db).await {
+                    if r.evaluate_cached(user, db, as_of, cache).await {
                         all = true;
                         break;
                     }
@@ -63,7 +510,7 @@ impl Rights {
             Self::NoneOf(rights) => !{
                 let mut all = false;
                 for r in rights.iter() {
-                    if r.evaluate(user, db).await {
+                    if r.evaluate_cached(user, db, as_of, cache).await {
                         all = true;
                         break;
                     }
@@ -71,57 +518,799 @@ impl Rights {
 
                 all
             },
-            Self::Permission(perm) => user.has(&perm[..], db).await,
+            Self::AtLeast(n, rights) => {
+                let mut passed = 0;
+
+                // Stops once n passed or n can no longer be reached.
+                for (i, r) in rights.iter().enumerate() {
+                    if passed >= *n || passed + (rights.len() - i) < *n {
+                        break;
+                    }
+
+                    if r.evaluate_cached(user, db, as_of, cache).await {
+                        passed += 1;
+                    }
+                }
+
+                passed >= *n
+            }
+            Self::ExactlyOne(rights) => {
+                Self::prefetch(rights, user, db, as_of, cache).await;
+
+                let mut passed = 0;
+
+                // Stops at a second pass, otherwise every child is checked to rule one out.
+                for r in rights.iter() {
+                    if r.evaluate_cached(user, db, as_of, cache).await {
+                        passed += 1;
+
+                        if passed > 1 {
+                            break;
+                        }
+                    }
+                }
+
+                passed == 1
+            }
+            Self::Permission(perm) => {
+                let key = (DEFAULT_NAMESPACE.to_string(), perm.as_ref().to_string());
+
+                if let Some(has) = cache.get(&key) {
+                    return *has;
+                }
+
+                let has = user.has_as_of(perm.as_ref(), as_of, db).await;
+                cache.insert(key, has);
+                has
+            }
+            Self::NamespacedPermission(namespace, perm) => {
+                let key = (namespace.clone(), perm.as_ref().to_string());
+
+                if let Some(has) = cache.get(&key) {
+                    return *has;
+                }
+
+                let has = user.has_in_as_of(namespace, perm.as_ref(), as_of, db).await;
+                cache.insert(key, has);
+                has
+            }
+            Self::Quota { name, limit } => match user.as_quota() {
+                Some(quota) => quota.current(name, db).await < *limit,
+                None => false,
+            },
+            Self::Custom(right) => right.check(user, db).await,
             Self::None => false,
         }
     }
 }
 
-pub struct Auth<D>
+/// The permissions held by only one or both of two users.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PermissionDiff {
+    pub only_a: Vec<String>,
+    pub only_b: Vec<String>,
+    pub both: Vec<String>,
+}
+
+/// Compares the permissions of two users using HasPermission::permissions, for access reviews.
+/// This enumerates the permissions of both users so costs two permission lookups.
+pub async fn permission_diff(
+    a: &(dyn HasPermission + Sync),
+    b: &(dyn HasPermission + Sync),
+    pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+) -> PermissionDiff {
+    let a_perms: BTreeSet<String> = a.permissions(pool).await.into_iter().collect();
+    let b_perms: BTreeSet<String> = b.permissions(pool).await.into_iter().collect();
+
+    PermissionDiff {
+        only_a: a_perms.difference(&b_perms).cloned().collect(),
+        only_b: b_perms.difference(&a_perms).cloned().collect(),
+        both: a_perms.intersection(&b_perms).cloned().collect(),
+    }
+}
+
+/// Fluent builder for composing a `Rights` tree.
+///
+/// `RightsBuilder::all().permission("a").any_of(|b| b.permission("x").permission("y")).build()`
+/// produces the same tree as
+/// `Rights::all(&[Rights::Permission("a".into()), Rights::any(&[Rights::Permission("x".into()), Rights::Permission("y".into())])])`.
+#[derive(Clone)]
+pub struct RightsBuilder<P = String> {
+    kind: BuilderKind,
+    rights: Vec<Rights<P>>,
+}
+
+#[derive(Clone, Copy)]
+enum BuilderKind {
+    All,
+    Any,
+    NoneOf,
+    AtLeast(usize),
+    ExactlyOne,
+}
+
+/// Creates a builder whose children must All pass.
+impl<P> Default for RightsBuilder<P> {
+    fn default() -> Self {
+        Self::with_kind(BuilderKind::All)
+    }
+}
+
+/// For typed permissions name the type, like `RightsBuilder::<AppPerm>::all()`.
+impl<P> RightsBuilder<P> {
+    /// Creates a builder whose children must All pass.
+    pub fn new() -> Self {
+        Self::all()
+    }
+
+    /// Creates a builder whose children must All pass.
+    pub fn all() -> Self {
+        Self::with_kind(BuilderKind::All)
+    }
+
+    /// Creates a builder where Any child passing is enough.
+    pub fn any() -> Self {
+        Self::with_kind(BuilderKind::Any)
+    }
+
+    /// Creates a builder where None of the children may pass.
+    pub fn none() -> Self {
+        Self::with_kind(BuilderKind::NoneOf)
+    }
+
+    /// Creates a builder where at least n of the children must pass.
+    pub fn at_least(n: usize) -> Self {
+        Self::with_kind(BuilderKind::AtLeast(n))
+    }
+
+    /// Creates a builder where exactly one of the children must pass.
+    pub fn exactly_one() -> Self {
+        Self::with_kind(BuilderKind::ExactlyOne)
+    }
+
+    fn with_kind(kind: BuilderKind) -> Self {
+        Self {
+            kind,
+            rights: Vec::new(),
+        }
+    }
+
+    /// Adds a Permission leaf.
+    pub fn permission(mut self, perm: impl Into<P>) -> Self {
+        self.rights.push(Rights::Permission(perm.into()));
+        self
+    }
+
+    /// Adds a NamespacedPermission leaf.
+    pub fn namespaced(mut self, namespace: impl Into<String>, perm: impl Into<P>) -> Self {
+        self.rights.push(Rights::NamespacedPermission(namespace.into(), perm.into()));
+        self
+    }
+
+    /// Adds a Quota leaf.
+    pub fn quota(mut self, name: impl Into<String>, limit: i64) -> Self {
+        self.rights.push(Rights::Quota {
+            name: name.into(),
+            limit,
+        });
+        self
+    }
+
+    /// Adds a Custom leaf.
+    pub fn custom(mut self, right: impl CustomRight + 'static) -> Self {
+        self.rights.push(Rights::Custom(Arc::new(right)));
+        self
+    }
+
+    /// Adds an already built Rights as a child.
+    pub fn right(mut self, right: Rights<P>) -> Self {
+        self.rights.push(right);
+        self
+    }
+
+    /// Adds a nested All group built by the closure.
+    pub fn all_of<F>(self, f: F) -> Self
+    where
+        F: FnOnce(RightsBuilder<P>) -> RightsBuilder<P>,
+    {
+        self.right(f(RightsBuilder::with_kind(BuilderKind::All)).build())
+    }
+
+    /// Adds a nested Any group built by the closure.
+    pub fn any_of<F>(self, f: F) -> Self
+    where
+        F: FnOnce(RightsBuilder<P>) -> RightsBuilder<P>,
+    {
+        self.right(f(RightsBuilder::with_kind(BuilderKind::Any)).build())
+    }
+
+    /// Adds a nested NoneOf group built by the closure.
+    pub fn none_of<F>(self, f: F) -> Self
+    where
+        F: FnOnce(RightsBuilder<P>) -> RightsBuilder<P>,
+    {
+        self.right(f(RightsBuilder::with_kind(BuilderKind::NoneOf)).build())
+    }
+
+    /// Adds a nested AtLeast group built by the closure.
+    pub fn at_least_of<F>(self, n: usize, f: F) -> Self
+    where
+        F: FnOnce(RightsBuilder<P>) -> RightsBuilder<P>,
+    {
+        self.right(f(RightsBuilder::with_kind(BuilderKind::AtLeast(n))).build())
+    }
+
+    /// Adds a nested ExactlyOne group built by the closure.
+    pub fn exactly_one_of<F>(self, f: F) -> Self
+    where
+        F: FnOnce(RightsBuilder<P>) -> RightsBuilder<P>,
+    {
+        self.right(f(RightsBuilder::with_kind(BuilderKind::ExactlyOne)).build())
+    }
+
+    /// Consumes the builder returning the composed Rights.
+    pub fn build(self) -> Rights<P> {
+        let rights = self.rights.into_boxed_slice();
+
+        match self.kind {
+            BuilderKind::All => Rights::All(rights),
+            BuilderKind::Any => Rights::Any(rights),
+            BuilderKind::NoneOf => Rights::NoneOf(rights),
+            BuilderKind::AtLeast(n) => Rights::AtLeast(n, rights),
+            BuilderKind::ExactlyOne => Rights::ExactlyOne(rights),
+        }
+    }
+}
+
+/// Controls how being authenticated interacts with the Rights of an Auth.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthGate {
+    /// The user must be authenticated and pass the Rights.
+    RequireAuthThenRights,
+    /// Only the Rights are checked, anonymous users may pass them.
+    RightsOnly,
+    /// Authenticated users pass, anonymous users must pass the Rights.
+    RequireAuthOrRights,
+}
+
+/// Why Auth::validate_detailed allowed or denied a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthResult {
+    Authorized,
+    /// The user must be authenticated.
+    NotAuthenticated,
+    /// The method is not allowed by the Auth.
+    MethodNotAllowed,
+    /// The user did not pass the Rights.
+    MissingRights,
+}
+
+impl AuthResult {
+    /// The matching response Status, Ok when authorized.
+    pub fn status(&self) -> Status {
+        match self {
+            AuthResult::Authorized => Status::Ok,
+            AuthResult::NotAuthenticated => Status::Unauthorized,
+            AuthResult::MethodNotAllowed => Status::MethodNotAllowed,
+            AuthResult::MissingRights => Status::Forbidden,
+        }
+    }
+}
+
+pub struct Auth<D, P = String>
 where
     D: 'static + SQLxSessionAuth<D> + HasPermission,
 {
-    pub rights: Rights,
-    pub auth_required: bool,
+    pub rights: Rights<P>,
+    /// Rights used instead of rights for requests with the method.
+    pub method_rights: HashMap<Method, Rights<P>>,
+    pub gate: AuthGate,
     pub methods: Box<[Method]>,
+    /// If every method is allowed, ignoring methods.
+    pub any_method: bool,
+    /// Methods that are always allowed, even for anonymous users.
+    pub anonymous_methods: Box<[Method]>,
+    /// If the Rights still need to pass for anonymous_methods.
+    pub anonymous_rights: bool,
     phantom: PhantomData<D>,
 }
 
-impl<D> Auth<D>
+impl<D, P> Auth<D, P>
 where
     D: 'static + SQLxSessionAuth<D> + HasPermission,
+    P: AsRef<str> + Clone + Send + Sync,
 {
-    pub fn build(methods: &[Method], auth_req: bool) -> Auth<D> {
-        Auth::<D> {
+    pub fn build(methods: &[Method], auth_req: bool) -> Auth<D, P> {
+        Auth::<D, P> {
             rights: Rights::None,
-            auth_required: auth_req,
+            method_rights: HashMap::new(),
+            gate: if auth_req {
+                AuthGate::RequireAuthThenRights
+            } else {
+                AuthGate::RightsOnly
+            },
             methods: methods.into(),
+            any_method: false,
+            anonymous_methods: Box::new([]),
+            anonymous_rights: false,
             phantom: PhantomData,
         }
     }
 
-    pub fn requires(&mut self, rights: Rights) -> &mut Self {
+    /// Like build but allows every method, so the gate and Rights apply to all of them.
+    /// auth_req still requires an authenticated user, and anonymous methods still skip it.
+    pub fn any_method(auth_req: bool) -> Auth<D, P> {
+        let mut auth = Auth::build(&[], auth_req);
+        auth.any_method = true;
+        auth
+    }
+
+    pub fn requires(&mut self, rights: Rights<P>) -> &mut Self {
         self.rights = rights;
         self
     }
 
+    /// Sets the Rights for requests with the method, replacing the requires Rights for them.
+    /// The method must still be allowed by build or any_method.
+    pub fn requires_for(&mut self, method: Method, rights: Rights<P>) -> &mut Self {
+        self.method_rights.insert(method, rights);
+        self
+    }
+
+    /// The Rights checked for requests with the method.
+    pub fn rights_for(&self, method: &Method) -> &Rights<P> {
+        self.method_rights.get(method).unwrap_or(&self.rights)
+    }
+
+    /// Sets how authentication and the Rights combine.
+    pub fn gate(&mut self, gate: AuthGate) -> &mut Self {
+        self.gate = gate;
+        self
+    }
+
+    /// Sets the methods anyone may use regardless of the gate, like a public GET.
+    /// If with_rights is true the Rights must still pass for these methods.
+    pub fn anonymous(&mut self, methods: &[Method], with_rights: bool) -> &mut Self {
+        self.anonymous_methods = methods.into();
+        self.anonymous_rights = with_rights;
+        self
+    }
+
+    /// Checks the user as given, guards use validate_for to also honor the requests
+    /// PermissionCache.
     pub async fn validate(
         &self,
         user: &D,
         method: &Method,
-        db: Option<&mut PoolConnection<sqlx::Postgres>>,
+        db: Option<&mut PoolConnection<SqlxDatabase>>,
     ) -> bool
     where
         D: HasPermission + SQLxSessionAuth<D> + Sync,
     {
-        if self.auth_required && !user.is_authenticated() {
-            return false;
+        matches!(
+            self.validate_detailed(user, method, db).await,
+            AuthResult::Authorized
+        )
+    }
+
+    /// Like validate but returns why the request was denied.
+    pub async fn validate_detailed(
+        &self,
+        user: &D,
+        method: &Method,
+        db: Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> AuthResult
+    where
+        D: HasPermission + SQLxSessionAuth<D> + Sync,
+    {
+        self.validate_as(user, user, method, db).await
+    }
+
+    /// Like validate_detailed for the current user of the request, checking the Rights against
+    /// SQLxAuth::permission_user so the PermissionCache is used. Without a current user this is
+    /// NotAuthenticated.
+    pub async fn validate_for(
+        &self,
+        auth: &SQLxAuth<D>,
+        method: &Method,
+        db: Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> AuthResult
+    where
+        D: Clone + Send + Sync,
+    {
+        match (&auth.current_user, auth.permission_user()) {
+            (Some(user), Some(perms)) => self.validate_as(user, &perms, method, db).await,
+            _ => AuthResult::NotAuthenticated,
+        }
+    }
+
+    /// Validates with the users authentication while the Rights are checked against perms.
+    async fn validate_as(
+        &self,
+        user: &D,
+        perms: &(dyn HasPermission + Sync),
+        method: &Method,
+        db: Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> AuthResult
+    where
+        D: Sync,
+    {
+        let rights = self.rights_for(method);
+
+        if self.anonymous_methods.iter().any(|r| r == method) {
+            return if !self.anonymous_rights || rights.evaluate(perms, &db).await {
+                AuthResult::Authorized
+            } else {
+                AuthResult::MissingRights
+            };
+        }
+
+        if !self.any_method && !self.methods.iter().any(|r| r == method) {
+            return AuthResult::MethodNotAllowed;
         }
 
-        if self.methods.iter().any(|r| r == method) {
-            self.rights.evaluate(user, &db).await
+        match self.gate {
+            AuthGate::RequireAuthThenRights if !user.is_authenticated() => {
+                AuthResult::NotAuthenticated
+            }
+            AuthGate::RequireAuthOrRights if user.is_authenticated() => AuthResult::Authorized,
+            _ if rights.evaluate(perms, &db).await => AuthResult::Authorized,
+            // Logging in would let them pass.
+            AuthGate::RequireAuthOrRights => AuthResult::NotAuthenticated,
+            _ => AuthResult::MissingRights,
+        }
+    }
+}
+
+/// Every method an any_method policy may be requested with.
+const ANY_METHODS: [Method; 9] = [
+    Method::Get,
+    Method::Put,
+    Method::Post,
+    Method::Delete,
+    Method::Options,
+    Method::Head,
+    Method::Trace,
+    Method::Connect,
+    Method::Patch,
+];
+
+/// Returns the names of the policies the user passes with at least one of their methods, for
+/// building access review pages. any_method policies are checked for every method with its own
+/// Rights plus one method using the default Rights. Every policy is evaluated in full, so this
+/// runs all of their permission checks and can be expensive for many policies.
+pub async fn accessible_policies<'a, D, P>(
+    user: &D,
+    policies: &[(&'a str, &Auth<D, P>)],
+    mut db: Option<&mut PoolConnection<SqlxDatabase>>,
+) -> Vec<&'a str>
+where
+    D: 'static + SQLxSessionAuth<D> + HasPermission + Sync,
+    P: AsRef<str> + Clone + Send + Sync,
+{
+    let mut accessible = Vec::new();
+
+    for (name, auth) in policies {
+        let methods: Vec<&Method> = if auth.any_method {
+            let default = ANY_METHODS
+                .iter()
+                .find(|method| !auth.method_rights.contains_key(*method));
+
+            auth.method_rights.keys().chain(default).collect()
         } else {
+            auth.methods.iter().chain(auth.anonymous_methods.iter()).collect()
+        };
+
+        for method in methods {
+            if auth.validate(user, method, db.as_deref_mut()).await {
+                accessible.push(*name);
+                break;
+            }
+        }
+    }
+
+    accessible
+}
+
+/// Creates a zero sized request guard that only succeeds when the current user passes the Rights.
+/// Requests that fail the Rights are Forwarded so a handler taking the guard documents and enforces
+/// its requirement in its signature. The Rights are checked with a connection from the users
+/// pool and the PermissionCache, failing with 503 when no connection could be acquired.
+///
+/// `permission_guard!(pub AdminOnly, User, Rights::Permission("Token::UseAdmin".into()));`
+///
+/// Typed permissions pass their type before the Rights.
+/// `permission_guard!(pub AdminOnly, User, AppPerm, Rights::Permission(AppPerm::Admin));`
+#[macro_export]
+macro_rules! permission_guard {
+    ($vis:vis $name:ident, $user:ty, $rights:expr) => {
+        $crate::permission_guard!($vis $name, $user, ::std::string::String, $rights);
+    };
+    ($vis:vis $name:ident, $user:ty, $perm:ty, $rights:expr) => {
+        $vis struct $name;
+
+        #[::rocket::async_trait]
+        impl<'r> ::rocket::request::FromRequest<'r> for $name {
+            type Error = ();
+
+            async fn from_request(
+                request: &'r ::rocket::request::Request<'_>,
+            ) -> ::rocket::request::Outcome<Self, Self::Error> {
+                let auth = ::rocket::outcome::try_outcome!(
+                    request.guard::<$crate::SQLxAuth<$user>>().await
+                );
+
+                let user = match auth.permission_user() {
+                    Some(user) => user,
+                    None => return ::rocket::outcome::Outcome::Forward(()),
+                };
+
+                let authpool = ::rocket::outcome::try_outcome!(
+                    request
+                        .guard::<&::rocket::State<$crate::SQLxSessionAuthPool<$user>>>()
+                        .await
+                );
+                let mut conn =
+                    ::rocket::outcome::try_outcome!(authpool.request_connection(request).await);
+
+                let rights: $crate::Rights<$perm> = $rights;
+
+                if rights.evaluate(&user, &Some(&mut conn)).await {
+                    ::rocket::outcome::Outcome::Success($name)
+                } else {
+                    ::rocket::outcome::Outcome::Forward(())
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Grants the listed permissions, counting every has and has_all call.
+    struct Perms {
+        granted: &'static [&'static str],
+        batching: bool,
+        calls: AtomicUsize,
+        batch_calls: AtomicUsize,
+    }
+
+    impl Perms {
+        fn new(granted: &'static [&'static str]) -> Self {
+            Self {
+                granted,
+                batching: false,
+                calls: AtomicUsize::new(0),
+                batch_calls: AtomicUsize::new(0),
+            }
+        }
+
+        fn batching(mut self) -> Self {
+            self.batching = true;
+            self
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn batch_calls(&self) -> usize {
+            self.batch_calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[rocket::async_trait]
+    impl HasPermission for Perms {
+        async fn has(&self, perm: &str, _pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.granted.contains(&perm)
+        }
+
+        async fn has_all(
+            &self,
+            perms: &[&str],
+            _pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+        ) -> Vec<bool> {
+            self.batch_calls.fetch_add(1, Ordering::SeqCst);
+            perms.iter().map(|perm| self.granted.contains(perm)).collect()
+        }
+
+        fn batches(&self) -> bool {
+            self.batching
+        }
+    }
+
+    fn perms(names: &[&str]) -> Vec<Rights> {
+        names.iter().map(|name| Rights::Permission(name.to_string())).collect()
+    }
+
+    #[derive(Clone)]
+    enum AppPerm {
+        Admin,
+        Editor,
+    }
+
+    impl AsRef<str> for AppPerm {
+        fn as_ref(&self) -> &str {
+            match self {
+                AppPerm::Admin => "admin",
+                AppPerm::Editor => "editor",
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn exactly_one_with_typed_permissions() {
+        let rights = Rights::exactly_one(&[
+            Rights::Permission(AppPerm::Admin),
+            Rights::Permission(AppPerm::Editor),
+        ]);
+
+        assert!(rights.evaluate(&Perms::new(&["admin"]), &None).await);
+        assert!(!rights.evaluate(&Perms::new(&["admin", "editor"]), &None).await);
+        assert!(!rights.evaluate(&Perms::new(&[]), &None).await);
+
+        let built = RightsBuilder::<AppPerm>::exactly_one()
+            .permission(AppPerm::Admin)
+            .permission(AppPerm::Editor)
+            .build();
+
+        assert!(built.evaluate(&Perms::new(&["editor"]), &None).await);
+    }
+
+    #[tokio::test]
+    async fn groups_short_circuit_without_batching() {
+        let user = Perms::new(&["a"]);
+        let any = Rights::any(&perms(&["a", "b", "c"]));
+
+        assert!(any.evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 1);
+        assert_eq!(user.batch_calls(), 0);
+
+        let user = Perms::new(&[]);
+        let all = Rights::all(&perms(&["a", "b", "c"]));
+
+        assert!(!all.evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn batching_users_prefetch_with_has_all() {
+        let user = Perms::new(&["b"]).batching();
+        let any = Rights::any(&perms(&["a", "b", "c"]));
+
+        assert!(any.evaluate(&user, &None).await);
+        assert_eq!(user.batch_calls(), 1);
+        assert_eq!(user.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn at_least_zero_always_passes() {
+        let user = Perms::new(&[]);
+
+        assert!(Rights::at_least(0, &perms(&["a", "b"])).evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn at_least_more_than_given_never_passes() {
+        let user = Perms::new(&["a", "b"]);
+
+        assert!(!Rights::at_least(3, &perms(&["a", "b"])).evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn at_least_stops_once_decided() {
+        let user = Perms::new(&["a", "b"]);
+        assert!(Rights::at_least(2, &perms(&["a", "b", "c", "d"])).evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 2);
+
+        let user = Perms::new(&[]);
+        assert!(!Rights::at_least(2, &perms(&["a", "b", "c"])).evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn repeated_permissions_are_looked_up_once() {
+        let user = Perms::new(&["a"]);
+        let rights = Rights::all(&[
+            Rights::Permission("a".to_string()),
+            Rights::any(&perms(&["a", "a"])),
+            Rights::at_least(1, &perms(&["a"])),
+            Rights::none(&[Rights::none(&perms(&["a"]))]),
+        ]);
+
+        assert!(rights.evaluate(&user, &None).await);
+        assert_eq!(user.calls(), 1);
+    }
+
+    /// A user holding a and b whose "reader" role only grants a.
+    struct Reader {
+        roles: bool,
+    }
+
+    #[rocket::async_trait]
+    impl HasRoles for Reader {
+        async fn role_has(
+            &self,
+            role: &str,
+            perm: &str,
+            _pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+        ) -> bool {
+            role == "reader" && perm == "a"
+        }
+    }
+
+    #[rocket::async_trait]
+    impl HasPermission for Reader {
+        async fn has(&self, perm: &str, _pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+            perm == "a" || perm == "b"
+        }
+
+        fn as_roles(&self) -> Option<&(dyn HasRoles + Sync)> {
+            if self.roles {
+                Some(self)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn previews_only_grant_what_the_role_and_user_share() {
+        let user = Reader { roles: true };
+        let preview = PermissionUser::Preview(RolePreview::new(&user, "reader"));
+
+        assert!(preview.has("a", &None).await);
+        assert!(!preview.has("b", &None).await);
+        assert!(!preview.has("c", &None).await);
+        assert!(PermissionUser::User(&user).has("b", &None).await);
+    }
+
+    #[tokio::test]
+    async fn previews_deny_without_roles() {
+        let user = Reader { roles: false };
+        let preview = RolePreview::new(&user, "reader");
+
+        assert!(!preview.has("a", &None).await);
+    }
+
+    /// Held the beta flag only until its cut off.
+    struct Beta {
+        until: DateTime<Utc>,
+    }
+
+    #[rocket::async_trait]
+    impl HasPermission for Beta {
+        async fn has(&self, _: &str, _pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
             false
         }
+
+        async fn has_in_as_of(
+            &self,
+            namespace: &str,
+            perm: &str,
+            as_of: Option<DateTime<Utc>>,
+            _pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+        ) -> bool {
+            let at = as_of.unwrap_or_else(Utc::now);
+            namespace == "flags" && perm == "beta" && at < self.until
+        }
+    }
+
+    #[tokio::test]
+    async fn namespaced_permissions_are_checked_as_of() {
+        let until = Utc::now() - chrono::Duration::days(1);
+        let user = Beta { until };
+        let rights: Rights = Rights::NamespacedPermission("flags".into(), "beta".into());
+
+        let before = until - chrono::Duration::hours(1);
+
+        assert!(rights.evaluate_as_of(&user, &None, Some(before)).await);
+        assert!(!rights.evaluate(&user, &None).await);
     }
 }
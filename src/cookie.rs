@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket_sqlxsession::SqlxSessionConfig;
+use std::{sync::Arc, time::Duration};
+
+/// Cookie attributes for the Session cookie set on login. Attributes left as None keep whatever
+/// rocket_sqlxsession set on the cookie.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoginOptions {
+    /// How long the cookie and the server side session live, for example on "remember me".
+    pub max_age: Option<Duration>,
+    /// Some(false) makes the cookie a session cookie that ends the login when the browser
+    /// closes, ignoring max_age. Some(true) keeps the cookie for max_age.
+    pub persistent: Option<bool>,
+    pub same_site: Option<SameSite>,
+    pub secure: Option<bool>,
+    pub http_only: Option<bool>,
+}
+
+impl LoginOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Sets if the login survives the browser closing, for example from a "remember me" checkbox.
+    pub fn with_persistent(mut self, persistent: bool) -> Self {
+        self.persistent = Some(persistent);
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = Some(secure);
+        self
+    }
+
+    pub fn with_http_only(mut self, http_only: bool) -> Self {
+        self.http_only = Some(http_only);
+        self
+    }
+
+    /// The new server side expiry if max_age outlives the current one.
+    /// Session cookies are never extended so the server side session does not outlive them.
+    pub(crate) fn extended_expiry(&self, expires: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.persistent == Some(false) {
+            return None;
+        }
+
+        let max_age = chrono::Duration::from_std(self.max_age?).ok()?;
+        let extended = Utc::now().checked_add_signed(max_age)?;
+
+        if extended > expires {
+            Some(extended)
+        } else {
+            None
+        }
+    }
+
+    /// Rewrites the Session cookie with these attributes. The cookie rocket_sqlxsession added to
+    /// this response is reused so there is only ever one write of the cookie per response,
+    /// otherwise the cookie is rebuilt from the Session stores config.
+    pub(crate) fn apply(&self, jar: &CookieJar<'_>, config: &SqlxSessionConfig, session_id: &str) {
+        if session_id.is_empty() {
+            return;
+        }
+
+        let mut cookie = jar
+            .get_pending(&config.cookie_name)
+            .unwrap_or_else(|| session_cookie(config, session_id));
+
+        if self.persistent == Some(false) {
+            cookie.unset_max_age();
+            cookie.unset_expires();
+        } else if let Some(max_age) = self.max_age {
+            let seconds = max_age.as_secs().min(i64::MAX as u64) as i64;
+            cookie.set_max_age(rocket::time::Duration::seconds(seconds));
+        }
+
+        if let Some(same_site) = self.same_site {
+            cookie.set_same_site(same_site);
+        }
+
+        if let Some(secure) = self.secure {
+            cookie.set_secure(secure);
+        }
+
+        if let Some(http_only) = self.http_only {
+            cookie.set_http_only(http_only);
+        }
+
+        jar.add(cookie);
+    }
+}
+
+/// The Session cookie as rocket_sqlxsession sets it.
+fn session_cookie(config: &SqlxSessionConfig, session_id: &str) -> Cookie<'static> {
+    let mut cookie = Cookie::new(config.cookie_name.clone(), session_id.to_string());
+
+    cookie.set_path(config.cookie_path.clone());
+    cookie.set_http_only(config.cookie_http_only);
+    cookie.set_secure(config.cookie_secure);
+    cookie.set_same_site(config.cookie_same_site);
+
+    if let Some(domain) = &config.cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+
+    cookie
+}
+
+/// The LoginOptions of a login during the current request, applied once the response is sent.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PendingLogin(Arc<Mutex<Option<LoginOptions>>>);
+
+impl PendingLogin {
+    pub(crate) fn set(&self, options: LoginOptions) {
+        *self.0.lock() = Some(options);
+    }
+
+    pub(crate) fn take(&self) -> Option<LoginOptions> {
+        self.0.lock().take()
+    }
+}
@@ -0,0 +1,74 @@
+use std::{error::Error, fmt};
+
+/// The errors of the auth layer.
+#[derive(Debug)]
+pub enum AuthError {
+    /// The requests Session is not within the Session store, for example it was removed during
+    /// the request.
+    SessionMissing,
+    /// A feature was used without being enabled on the fairing, like remember me.
+    NotEnabled(&'static str),
+    /// A database connection could not be acquired.
+    PoolAcquire(sqlx::Error),
+    /// A database query failed, including the user not being found.
+    Sqlx(sqlx::Error),
+    /// A user id or Session value could not be (de)serialized.
+    Serde(serde_json::Error),
+    /// Any other error of load_user, a store or a hook.
+    Load(Box<dyn Error + Send + Sync>),
+}
+
+impl AuthError {
+    /// Wraps any other error as AuthError::Load. `AuthError::load(error)` also takes an
+    /// anyhow::Error or a message.
+    pub fn load(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        AuthError::Load(error.into())
+    }
+
+    /// The sqlx::Error behind the error, also looked for within Load.
+    pub fn as_sqlx(&self) -> Option<&sqlx::Error> {
+        match self {
+            AuthError::PoolAcquire(error) | AuthError::Sqlx(error) => Some(error),
+            AuthError::Load(error) => error.downcast_ref::<sqlx::Error>(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::SessionMissing => f.write_str("Session data unexpectedly missing"),
+            AuthError::NotEnabled(feature) => write!(f, "{} is not enabled", feature),
+            AuthError::PoolAcquire(error) => {
+                write!(f, "Could not acquire a database connection: {}", error)
+            }
+            AuthError::Sqlx(error) => write!(f, "Database error: {}", error),
+            AuthError::Serde(error) => write!(f, "Serialization error: {}", error),
+            AuthError::Load(error) => write!(f, "Could not load user: {}", error),
+        }
+    }
+}
+
+impl Error for AuthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AuthError::PoolAcquire(error) | AuthError::Sqlx(error) => Some(error),
+            AuthError::Serde(error) => Some(error),
+            AuthError::Load(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for AuthError {
+    fn from(error: sqlx::Error) -> Self {
+        AuthError::Sqlx(error)
+    }
+}
+
+impl From<serde_json::Error> for AuthError {
+    fn from(error: serde_json::Error) -> Self {
+        AuthError::Serde(error)
+    }
+}
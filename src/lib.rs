@@ -1,5 +1,40 @@
+#[cfg(not(any(feature = "postgres", feature = "mysql", feature = "sqlite")))]
+compile_error!("One of the postgres, mysql or sqlite features must be enabled.");
+
+// With several backend features enabled sqlite wins over mysql and mysql over postgres, so
+// picking one does not require disabling the default features and --all-features still builds.
+
+mod error;
 mod session;
 mod auth;
+mod magic_link;
+mod proxy;
+mod cookie;
+mod policy;
+mod remember;
+mod permission_cache;
+mod token;
+#[cfg(feature = "test-util")]
+mod test_util;
 
-pub use session::{SQLxAuth, SQLxSessionAuth, SQLxSessionAuthPool, SqlxSessionAuthFairing};
-pub use auth::{Auth, Rights, HasPermission};
\ No newline at end of file
+pub use error::AuthError;
+pub use session::{
+    AnonymousPolicy, AuthEvents, AuthEventsHook, AuthedUser, Callback, ErrorClassifier, FreshAuth,
+    LoadContext, LoadErrorAction, LoadedHook, RetryPolicy, SQLxAuth, SQLxSessionAuth,
+    SQLxSessionAuthPool, SqlxDatabase, SqlxPool, SqlxSessionAuthFairing, StrictAuth, TenantResolver,
+    DEFAULT_SESSION_KEY,
+};
+pub use auth::{
+    accessible_policies, permission_diff, Auth, AuthGate, AuthResult, CustomRight, HasPermission,
+    HasQuota, HasRoles, PermissionDiff, PermissionUser, Rights, RightsBuilder, RolePreview,
+    DEFAULT_NAMESPACE,
+};
+pub use magic_link::issue_magic_link;
+pub use proxy::ProxyHeaderResolver;
+pub use cookie::LoginOptions;
+pub use policy::{AuthPolicies, Authorized, AuthorizedBy, Policy, PolicyName};
+pub use remember::RememberMe;
+pub use permission_cache::{CachedPermissions, PermissionCache};
+pub use token::{MemoryTokenStore, TokenStore};
+#[cfg(feature = "test-util")]
+pub use test_util::{check_rights, AuthSnapshot};
\ No newline at end of file
@@ -0,0 +1,47 @@
+use crate::{
+    session::Result,
+    token::{generate_token, hash_token},
+    SQLxAuth, SQLxSessionAuth, TokenStore,
+};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+
+/// Creates a new single use token for the user that expires after lifespan.
+/// The returned token is what gets placed within the emailed link, only its hash is stored.
+pub async fn issue_magic_link<I: Serialize>(
+    store: &dyn TokenStore,
+    user_id: &I,
+    lifespan: Duration,
+) -> Result<String> {
+    let now = Utc::now();
+    let token = generate_token();
+    let user_id = serde_json::to_string(user_id)?;
+
+    let _ = store.clear_expired(now).await;
+    store
+        .insert(&hash_token(&token), &user_id, now + lifespan)
+        .await?;
+
+    Ok(token)
+}
+
+impl<D> SQLxAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    /// Consumes the magic link token and logs in its user. Returns false if the token
+    /// was unknown, expired or already used.
+    pub async fn login_with_magic_link(
+        &self,
+        store: &dyn TokenStore,
+        token: &str,
+    ) -> Result<bool> {
+        match store.consume(&hash_token(token), Utc::now()).await? {
+            Some(user_id) => {
+                self.login_user(serde_json::from_str(&user_id)?)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
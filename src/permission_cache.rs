@@ -0,0 +1,316 @@
+use crate::{HasPermission, HasQuota, HasRoles, SqlxDatabase, DEFAULT_NAMESPACE};
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use sqlx::pool::PoolConnection;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Caches permission lookups across requests, keyed by (tenant, user cache key) and then by
+/// namespace and permission. Shared by all of Rockets workers, clones share the same cache.
+#[derive(Clone, Debug)]
+pub struct PermissionCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    entries: Arc<RwLock<HashMap<UserKey, UserEntries>>>,
+    pruned: PruneTimer,
+}
+
+/// (tenant, user cache key).
+type UserKey = (Option<String>, String);
+
+/// The lookups of one user by namespace, then by permission.
+type UserEntries = HashMap<String, HashMap<String, (Instant, bool)>>;
+
+/// When a cache last removed its expired entries, so a cache only scans them once an interval
+/// passed instead of on every insert.
+#[derive(Clone, Debug)]
+pub(crate) struct PruneTimer(Arc<Mutex<Instant>>);
+
+impl PruneTimer {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// True at most once per interval, the interval then starts over.
+    pub(crate) fn due(&self, interval: Duration) -> bool {
+        let mut pruned = self.0.lock();
+
+        if pruned.elapsed() < interval {
+            return false;
+        }
+
+        *pruned = Instant::now();
+        true
+    }
+}
+
+impl PermissionCache {
+    /// Keeps granted and denied permissions for ttl.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            negative_ttl: ttl,
+            entries: Arc::new(RwLock::new(HashMap::new())),
+            pruned: PruneTimer::new(),
+        }
+    }
+
+    /// Keeps denied permissions for ttl instead, never longer than granted ones are kept.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl.min(self.ttl);
+        self
+    }
+
+    /// Drops every cached permission of the user within every tenant, for example after a role
+    /// change. user_key is the users SQLxSessionAuth::cache_key.
+    pub fn invalidate(&self, user_key: &str) {
+        self.entries.write().retain(|(_, key), _| key.as_str() != user_key);
+    }
+
+    /// Drops every cached permission.
+    pub fn clear(&self) {
+        self.entries.write().clear();
+    }
+
+    /// Removes the expired entries.
+    pub fn clear_expired(&self) {
+        let (ttl, negative_ttl) = (self.ttl, self.negative_ttl);
+
+        self.entries.write().retain(|_, namespaces| {
+            namespaces.retain(|_, perms| {
+                perms.retain(|_, (at, has)| {
+                    at.elapsed() < if *has { ttl } else { negative_ttl }
+                });
+                !perms.is_empty()
+            });
+            !namespaces.is_empty()
+        });
+    }
+
+    /// Wraps the user of the tenant so its permission lookups go through the cache. Tenants
+    /// never share cached permissions, even for equal user keys.
+    pub fn user<'a, D>(
+        &'a self,
+        user: &'a D,
+        tenant: Option<&str>,
+        user_key: impl Into<String>,
+    ) -> CachedPermissions<'a, D> {
+        CachedPermissions {
+            user,
+            key: (tenant.map(String::from), user_key.into()),
+            cache: self,
+        }
+    }
+
+    fn get(&self, key: &UserKey, namespace: &str, perm: &str) -> Option<bool> {
+        let entries = self.entries.read();
+        let entry = entries
+            .get(key)
+            .and_then(|namespaces| namespaces.get(namespace))
+            .and_then(|perms| perms.get(perm));
+
+        match entry {
+            Some((at, true)) if at.elapsed() < self.ttl => Some(true),
+            Some((at, false)) if at.elapsed() < self.negative_ttl => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Inserts the lookup, removing the expired entries at most once per ttl.
+    fn insert(&self, key: &UserKey, namespace: &str, perm: &str, has: bool) {
+        if self.pruned.due(self.ttl) {
+            self.clear_expired();
+        }
+
+        self.entries
+            .write()
+            .entry(key.clone())
+            .or_default()
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(perm.to_string(), (Instant::now(), has));
+    }
+}
+
+/// A user whose has, has_in and has_all lookups are answered from a PermissionCache when
+/// possible. Lookups as of a time and permissions are always passed to the user.
+pub struct CachedPermissions<'a, D> {
+    user: &'a D,
+    key: UserKey,
+    cache: &'a PermissionCache,
+}
+
+impl<'a, D> CachedPermissions<'a, D> {
+    pub fn user(&self) -> &D {
+        self.user
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, D> HasPermission for CachedPermissions<'a, D>
+where
+    D: HasPermission + Sync,
+{
+    async fn has(&self, perm: &str, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+        if let Some(has) = self.cache.get(&self.key, DEFAULT_NAMESPACE, perm) {
+            return has;
+        }
+
+        let has = self.user.has(perm, pool).await;
+        self.cache.insert(&self.key, DEFAULT_NAMESPACE, perm, has);
+        has
+    }
+
+    async fn has_as_of(
+        &self,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        match as_of {
+            Some(_) => self.user.has_as_of(perm, as_of, pool).await,
+            None => self.has(perm, pool).await,
+        }
+    }
+
+    async fn has_in(
+        &self,
+        namespace: &str,
+        perm: &str,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        if let Some(has) = self.cache.get(&self.key, namespace, perm) {
+            return has;
+        }
+
+        let has = self.user.has_in(namespace, perm, pool).await;
+        self.cache.insert(&self.key, namespace, perm, has);
+        has
+    }
+
+    async fn has_in_as_of(
+        &self,
+        namespace: &str,
+        perm: &str,
+        as_of: Option<DateTime<Utc>>,
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> bool {
+        match as_of {
+            Some(_) => self.user.has_in_as_of(namespace, perm, as_of, pool).await,
+            None => self.has_in(namespace, perm, pool).await,
+        }
+    }
+
+    async fn has_all(
+        &self,
+        perms: &[&str],
+        pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> Vec<bool> {
+        let mut results: Vec<Option<bool>> = perms
+            .iter()
+            .map(|perm| self.cache.get(&self.key, DEFAULT_NAMESPACE, perm))
+            .collect();
+
+        let missing: Vec<&str> = perms
+            .iter()
+            .zip(&results)
+            .filter(|(_, has)| has.is_none())
+            .map(|(perm, _)| *perm)
+            .collect();
+
+        if !missing.is_empty() {
+            let mut fetched = self.user.has_all(&missing, pool).await.into_iter();
+
+            for (perm, result) in perms.iter().zip(results.iter_mut()) {
+                if result.is_none() {
+                    let has = fetched.next().unwrap_or(false);
+
+                    self.cache.insert(&self.key, DEFAULT_NAMESPACE, perm, has);
+                    *result = Some(has);
+                }
+            }
+        }
+
+        results.into_iter().map(|has| has.unwrap_or(false)).collect()
+    }
+
+    fn batches(&self) -> bool {
+        self.user.batches()
+    }
+
+    async fn permissions(&self, pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> Vec<String> {
+        self.user.permissions(pool).await
+    }
+
+    fn as_quota(&self) -> Option<&(dyn HasQuota + Sync)> {
+        self.user.as_quota()
+    }
+
+    fn as_roles(&self) -> Option<&(dyn HasRoles + Sync)> {
+        self.user.as_roles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(user_key: &str) -> UserKey {
+        (None, user_key.to_string())
+    }
+
+    #[test]
+    fn expired_entries_are_pruned_once_per_ttl() {
+        let cache = PermissionCache::new(Duration::from_secs(60))
+            .with_negative_ttl(Duration::from_millis(10));
+
+        cache.insert(&key("1"), DEFAULT_NAMESPACE, "denied", false);
+        std::thread::sleep(Duration::from_millis(20));
+        cache.insert(&key("2"), DEFAULT_NAMESPACE, "granted", true);
+
+        assert_eq!(cache.entries.read().len(), 2);
+        assert_eq!(cache.get(&key("1"), DEFAULT_NAMESPACE, "denied"), None);
+
+        cache.clear_expired();
+
+        assert_eq!(cache.entries.read().len(), 1);
+        assert_eq!(cache.get(&key("2"), DEFAULT_NAMESPACE, "granted"), Some(true));
+    }
+
+    #[test]
+    fn prune_timers_are_due_once_per_interval() {
+        let pruned = PruneTimer::new();
+
+        assert!(!pruned.due(Duration::from_millis(10)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(pruned.due(Duration::from_millis(10)));
+        assert!(!pruned.due(Duration::from_millis(10)));
+    }
+
+    /// Grants only the listed permission.
+    struct Grants(&'static str);
+
+    #[rocket::async_trait]
+    impl HasPermission for Grants {
+        async fn has(&self, perm: &str, _pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+            perm == self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn tenants_never_share_cached_permissions() {
+        let cache = PermissionCache::new(Duration::from_secs(60));
+        let (admin, member) = (Grants("admin"), Grants("member"));
+
+        assert!(cache.user(&admin, Some("a"), "1").has("admin", &None).await);
+        assert!(!cache.user(&member, Some("b"), "1").has("admin", &None).await);
+        assert!(cache.user(&member, Some("a"), "1").has("admin", &None).await);
+
+        cache.invalidate("1");
+
+        assert!(cache.entries.read().is_empty());
+    }
+}
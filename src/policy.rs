@@ -0,0 +1,333 @@
+use crate::{
+    Auth, AuthGate, AuthResult, HasPermission, Rights, SQLxAuth, SQLxSessionAuth,
+    SQLxSessionAuthPool, SqlxDatabase,
+};
+use rocket::{
+    http::{Method, Status},
+    outcome::{try_outcome, Outcome},
+    request::{FromRequest, Request},
+    State,
+};
+use sqlx::pool::PoolConnection;
+use std::{collections::HashMap, marker::PhantomData, ops::Deref, sync::Arc};
+
+/// A policy known at the type level, checked by the Authorized guard. Typed permissions
+/// implement `Policy<User, AppPerm>`.
+pub trait Policy<D, P = String> {
+    /// The methods the policy allows.
+    fn methods() -> &'static [Method];
+
+    /// The Rights the user must pass.
+    fn rights() -> Rights<P>;
+
+    /// How authentication and the Rights combine, defaults to requiring both.
+    fn gate() -> AuthGate {
+        AuthGate::RequireAuthThenRights
+    }
+}
+
+/// Request guard that loads the user and checks the policy T against the requests method with a
+/// connection from the users pool, giving the handler the authorized user. Fails with 401 when
+/// there is no user or the policy requires an authenticated one, 405 for a method the policy does
+/// not allow and 403 for missing rights. A connection that can not be acquired fails with 503.
+/// P is the policies permission type.
+pub struct Authorized<D, T, P = String> {
+    user: D,
+    phantom: PhantomData<fn() -> (T, P)>,
+}
+
+impl<D, T, P> Authorized<D, T, P> {
+    /// Takes the authorized user.
+    pub fn into_inner(self) -> D {
+        self.user
+    }
+}
+
+impl<D, T, P> Authorized<D, T, P>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D> + HasPermission,
+    T: 'static + Policy<D, P>,
+    P: 'static + AsRef<str> + Clone + Send + Sync,
+{
+    /// Validates the current user against the policy.
+    async fn check(
+        auth: &SQLxAuth<D>,
+        method: &Method,
+        db: Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> AuthResult {
+        let mut policy = Auth::<D, P>::build(T::methods(), false);
+        policy.gate(T::gate()).requires(T::rights());
+
+        policy.validate_for(auth, method, db).await
+    }
+}
+
+impl<D, T, P> Deref for Authorized<D, T, P> {
+    type Target = D;
+
+    fn deref(&self) -> &Self::Target {
+        &self.user
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, D, T, P> FromRequest<'r> for Authorized<D, T, P>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D> + HasPermission,
+    T: 'static + Policy<D, P>,
+    P: 'static + AsRef<str> + Clone + Send + Sync,
+{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let auth = try_outcome!(request.guard::<SQLxAuth<D>>().await);
+
+        if auth.current_user.is_none() {
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+
+        let authpool = try_outcome!(request.guard::<&State<SQLxSessionAuthPool<D>>>().await);
+        let mut conn = try_outcome!(authpool.request_connection(request).await);
+
+        match Self::check(&auth, &request.method(), Some(&mut conn)).await {
+            AuthResult::Authorized => authorized(auth.current_user, |user| Authorized {
+                user,
+                phantom: PhantomData,
+            }),
+            result => Outcome::Failure((result.status(), ())),
+        }
+    }
+}
+
+/// Named Auth policies managed by Rocket for the AuthorizedBy guard.
+/// `rocket.manage(AuthPolicies::<User>::new().with_policy("admin", admin))`, typed permissions
+/// use `AuthPolicies::<User, AppPerm>` with `AuthorizedBy<User, Admin, AppPerm>`.
+pub struct AuthPolicies<D, P = String>
+where
+    D: 'static + SQLxSessionAuth<D> + HasPermission,
+{
+    policies: HashMap<String, Arc<Auth<D, P>>>,
+}
+
+impl<D, P> Default for AuthPolicies<D, P>
+where
+    D: 'static + SQLxSessionAuth<D> + HasPermission,
+{
+    fn default() -> Self {
+        Self {
+            policies: HashMap::new(),
+        }
+    }
+}
+
+impl<D, P> AuthPolicies<D, P>
+where
+    D: 'static + SQLxSessionAuth<D> + HasPermission,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the policy under name, replacing any policy already using the name.
+    pub fn with_policy(mut self, name: &str, policy: Auth<D, P>) -> Self {
+        self.policies.insert(name.to_string(), Arc::new(policy));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Auth<D, P>> {
+        self.policies.get(name).map(|policy| policy.as_ref())
+    }
+}
+
+/// Names the AuthPolicies entry an AuthorizedBy guard checks.
+pub trait PolicyName {
+    const NAME: &'static str;
+}
+
+/// Request guard that loads the user and validates them with the AuthPolicies entry named by N,
+/// using the requests method and a connection from the users pool. The guard fails with the
+/// validate_for outcomes Status, 401 when not authenticated, 405 for a method the policy
+/// does not allow and 403 for missing rights. An unregistered policy fails with 500 and a
+/// connection that can not be acquired with 503. P is the AuthPolicies permission type.
+pub struct AuthorizedBy<D, N, P = String> {
+    user: D,
+    phantom: PhantomData<fn() -> (N, P)>,
+}
+
+impl<D, N, P> AuthorizedBy<D, N, P> {
+    /// Takes the authorized user.
+    pub fn into_inner(self) -> D {
+        self.user
+    }
+}
+
+impl<D, N, P> Deref for AuthorizedBy<D, N, P> {
+    type Target = D;
+
+    fn deref(&self) -> &Self::Target {
+        &self.user
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, D, N, P> FromRequest<'r> for AuthorizedBy<D, N, P>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D> + HasPermission,
+    N: 'static + PolicyName,
+    P: 'static + AsRef<str> + Clone + Send + Sync,
+{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let policies = try_outcome!(request.guard::<&State<AuthPolicies<D, P>>>().await);
+        let policy = match policies.get(N::NAME) {
+            Some(policy) => policy,
+            None => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let auth = try_outcome!(request.guard::<SQLxAuth<D>>().await);
+
+        if auth.current_user.is_none() {
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+
+        let authpool = try_outcome!(request.guard::<&State<SQLxSessionAuthPool<D>>>().await);
+        let mut conn = try_outcome!(authpool.request_connection(request).await);
+
+        match policy
+            .validate_for(&auth, &request.method(), Some(&mut conn))
+            .await
+        {
+            AuthResult::Authorized => authorized(auth.current_user, |user| AuthorizedBy {
+                user,
+                phantom: PhantomData,
+            }),
+            result => Outcome::Failure((result.status(), ())),
+        }
+    }
+}
+
+/// Hands the authorized user to the guard, 401 if there is none.
+fn authorized<D, G>(user: Option<D>, guard: impl FnOnce(D) -> G) -> Outcome<G, (Status, ()), ()> {
+    match user {
+        Some(user) => Outcome::Success(guard(user)),
+        None => Outcome::Failure((Status::Unauthorized, ())),
+    }
+}
+
+#[cfg(all(test, not(any(feature = "mysql", feature = "sqlite"))))]
+mod tests {
+    use super::*;
+    use crate::session::Result;
+    use rocket_sqlxsession::{SQLxSessionStore, SqlxSessionConfig};
+    use sqlx::postgres::PgPoolOptions;
+
+    #[derive(Clone, Debug)]
+    struct User {
+        admin: bool,
+        authenticated: bool,
+    }
+
+    #[rocket::async_trait]
+    impl SQLxSessionAuth<User> for User {
+        type Id = i64;
+
+        async fn load_user(_userid: i64, _pool: &mut PoolConnection<SqlxDatabase>) -> Result<User> {
+            Ok(User {
+                admin: false,
+                authenticated: true,
+            })
+        }
+
+        fn is_authenticated(&self) -> bool {
+            self.authenticated
+        }
+
+        fn is_active(&self) -> bool {
+            self.authenticated
+        }
+
+        fn is_anonymous(&self) -> bool {
+            !self.authenticated
+        }
+    }
+
+    #[rocket::async_trait]
+    impl HasPermission for User {
+        async fn has(&self, perm: &str, _pool: &Option<&mut PoolConnection<SqlxDatabase>>) -> bool {
+            self.admin && perm == "admin"
+        }
+    }
+
+    struct Admin;
+
+    impl Policy<User> for Admin {
+        fn methods() -> &'static [Method] {
+            &[Method::Get]
+        }
+
+        fn rights() -> Rights {
+            Rights::Permission("admin".to_string())
+        }
+    }
+
+    /// Checks the Admin policy for the user, the pool never connects.
+    async fn check(user: Option<User>, method: Method) -> AuthResult {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/test")
+            .unwrap();
+        let store = SQLxSessionStore::new(pool, SqlxSessionConfig::default());
+        let auth = SQLxAuth::detached(user.as_ref().map(|_| 1), user, store);
+
+        Authorized::<User, Admin>::check(&auth, &method, None).await
+    }
+
+    fn user(admin: bool, authenticated: bool) -> Option<User> {
+        Some(User {
+            admin,
+            authenticated,
+        })
+    }
+
+    #[tokio::test]
+    async fn authorized_passes_users_holding_the_rights() {
+        let result = check(user(true, true), Method::Get).await;
+
+        assert_eq!(result, AuthResult::Authorized);
+        assert_eq!(result.status(), Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn authorized_fails_with_the_matching_status() {
+        let missing = check(user(false, true), Method::Get).await;
+        let method = check(user(true, true), Method::Post).await;
+        let guest = check(user(true, false), Method::Get).await;
+        let nobody = check(None, Method::Get).await;
+
+        assert_eq!(missing.status(), Status::Forbidden);
+        assert_eq!(method.status(), Status::MethodNotAllowed);
+        assert_eq!(guest.status(), Status::Unauthorized);
+        assert_eq!(nobody.status(), Status::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn any_method_policies_are_accessible_through_any_method_with_rights() {
+        let mut delete = Auth::<User>::any_method(true);
+        delete
+            .requires(Rights::Permission("missing".to_string()))
+            .requires_for(Method::Delete, Rights::Permission("admin".to_string()));
+        let mut get = Auth::<User>::any_method(true);
+        get.requires(Rights::Permission("admin".to_string()))
+            .requires_for(Method::Get, Rights::Permission("missing".to_string()));
+        let policies = [("delete", &delete), ("get", &get)];
+
+        let admin = user(true, true).unwrap();
+        let member = user(false, true).unwrap();
+
+        assert_eq!(
+            crate::accessible_policies(&admin, &policies, None).await,
+            vec!["delete", "get"]
+        );
+        assert!(crate::accessible_policies(&member, &policies, None).await.is_empty());
+    }
+}
@@ -0,0 +1,42 @@
+use rocket::request::Request;
+use serde::de::DeserializeOwned;
+use std::net::IpAddr;
+
+/// Reads the user id from a header set by a trusted authenticating reverse proxy, like
+/// oauth2-proxy's X-Auth-Request-User. Requests from trusted proxies skip the Session lookup.
+#[derive(Debug, Clone)]
+pub struct ProxyHeaderResolver {
+    pub header: String,
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl ProxyHeaderResolver {
+    pub fn new(header: impl Into<String>, trusted_proxies: &[IpAddr]) -> Self {
+        Self {
+            header: header.into(),
+            trusted_proxies: trusted_proxies.to_vec(),
+        }
+    }
+
+    /// Checks the connecting address, not X-Real-IP, so the check itself can not be spoofed.
+    pub fn is_trusted(&self, request: &Request<'_>) -> bool {
+        match request.remote() {
+            Some(remote) => self.trusted_proxies.contains(&remote.ip()),
+            None => false,
+        }
+    }
+
+    /// Returns the user id within the header. Always None for requests not from a trusted proxy.
+    /// The header is read as json, falling back to a json string for ids like Uuids.
+    pub fn user_id<I: DeserializeOwned>(&self, request: &Request<'_>) -> Option<I> {
+        if !self.is_trusted(request) {
+            return None;
+        }
+
+        let id = request.headers().get_one(&self.header)?.trim();
+
+        serde_json::from_str(id)
+            .or_else(|_| serde_json::from_value(serde_json::Value::String(id.to_string())))
+            .ok()
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    session::Result,
+    token::{generate_token, hash_token},
+    AuthError, Callback, SQLxAuth, SQLxSessionAuth, TokenStore,
+};
+use chrono::{Duration, Utc};
+use parking_lot::Mutex;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// The private cookie holding the remember me token, named after the Session key so separate
+/// auth domains each keep their own.
+pub(crate) fn cookie_name(session_key: &str) -> String {
+    format!("{}_remember", session_key)
+}
+
+/// The remember me setup of the pool.
+#[derive(Clone, Debug)]
+pub struct RememberMe {
+    pub store: Callback<dyn TokenStore>,
+    /// How long a remember me login lasts after the user was last seen.
+    pub lifespan: Duration,
+}
+
+impl RememberMe {
+    /// Issues a new token for the user and sets it as the remember cookie named name, returning
+    /// the tokens hash.
+    pub(crate) async fn issue<I: Serialize>(
+        &self,
+        jar: &CookieJar<'_>,
+        name: &str,
+        user_id: &I,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let token = generate_token();
+        let token_hash = hash_token(&token);
+        let user_id = serde_json::to_string(user_id)?;
+
+        let _ = self.store.0.clear_expired(now).await;
+        self.store
+            .0
+            .insert(&token_hash, &user_id, now + self.lifespan)
+            .await?;
+
+        let cookie = Cookie::build(name.to_string(), token)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(rocket::time::Duration::seconds(self.lifespan.num_seconds()))
+            .finish();
+
+        jar.add_private(cookie);
+        Ok(token_hash)
+    }
+
+    /// Revokes the tokens by their hashes.
+    pub(crate) async fn revoke(&self, token_hashes: Vec<String>) {
+        let now = Utc::now();
+
+        for token_hash in token_hashes {
+            let _ = self.store.0.consume(&token_hash, now).await;
+        }
+    }
+
+    /// Consumes the remember cookies token returning its user id as json.
+    pub(crate) async fn consume(&self, jar: &CookieJar<'_>, name: &str) -> Option<String> {
+        let cookie = jar.get_private(name)?;
+
+        self.store
+            .0
+            .consume(&hash_token(cookie.value()), Utc::now())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Puts the consumed token of the remember cookie back for the user, after logging them back
+    /// in failed for a reason that may pass on a later request.
+    pub(crate) async fn restore<I: Serialize>(
+        &self,
+        jar: &CookieJar<'_>,
+        name: &str,
+        user_id: &I,
+    ) -> Result {
+        let cookie = match jar.get_private(name) {
+            Some(cookie) => cookie,
+            None => return Ok(()),
+        };
+        let user_id = serde_json::to_string(user_id)?;
+
+        self.store
+            .0
+            .insert(&hash_token(cookie.value()), &user_id, Utc::now() + self.lifespan)
+            .await
+    }
+
+    /// Consumes the remember cookies token and removes the cookie.
+    pub(crate) async fn forget(&self, jar: &CookieJar<'_>, name: &str) {
+        let _ = self.consume(jar, name).await;
+        jar.remove_private(Cookie::named(name.to_string()));
+    }
+}
+
+/// Set by logout_user so the response forgets the requests remember me token. logout_user has no
+/// access to the cookies so this is kept within the requests local cache for the fairing.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PendingForget(Arc<AtomicBool>);
+
+impl PendingForget {
+    pub(crate) fn set(&self, forget: bool) {
+        self.0.store(forget, Ordering::SeqCst);
+    }
+
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// The remember me tokens of Sessions evicted by login_user over the session cap. They are revoked
+/// once the response is sent, or right away by the async logins, so the evicted device is not
+/// logged back in by its remember cookie.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PendingRevoke(Arc<Mutex<Vec<String>>>);
+
+impl PendingRevoke {
+    pub(crate) fn push(&self, token_hash: String) {
+        self.0.lock().push(token_hash);
+    }
+
+    pub(crate) fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+impl<D> SQLxAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    /// Logs the user in and remembers them with a token cookie, so they are logged back in
+    /// once the Session is gone. The token is replaced every time it is used. Requires
+    /// SqlxSessionAuthFairing::with_remember_me.
+    pub async fn login_user_persistent(&self, id: D::Id, jar: &CookieJar<'_>) -> Result {
+        let remember = self
+            .remember()
+            .ok_or(AuthError::NotEnabled("Remember me"))?;
+
+        self.login_user(id.clone())?;
+        self.revoke_evicted().await;
+
+        let token_hash = remember
+            .issue(jar, &cookie_name(self.session_key()), &id)
+            .await?;
+        self.record_remember_token(token_hash);
+        Ok(())
+    }
+
+    /// Logs the user out and forgets their remember me token right away, instead of once the
+    /// response is sent like logout_user.
+    pub async fn logout_user_persistent(&self, jar: &CookieJar<'_>) -> Result {
+        if let Some(remember) = self.remember() {
+            remember.forget(jar, &cookie_name(self.session_key())).await;
+        }
+
+        self.logout_user()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryTokenStore;
+    use rocket::local::asynchronous::Client;
+
+    #[tokio::test]
+    async fn restored_tokens_log_the_user_in_again() {
+        let remember = RememberMe {
+            store: Callback(Arc::new(MemoryTokenStore::new())),
+            lifespan: Duration::days(1),
+        };
+        let client = Client::untracked(rocket::build()).await.unwrap();
+        let request = client
+            .get("/")
+            .private_cookie(Cookie::new("remember", "token"));
+        let jar = request.inner().cookies();
+
+        remember.restore(jar, "remember", &1).await.unwrap();
+
+        assert_eq!(remember.consume(jar, "remember").await, Some("1".to_string()));
+        assert_eq!(remember.consume(jar, "remember").await, None);
+    }
+}
@@ -1,63 +1,714 @@
+use crate::{
+    cookie::PendingLogin,
+    permission_cache::PruneTimer,
+    remember::{self, PendingForget, PendingRevoke},
+    AuthError, CachedPermissions, HasPermission, LoginOptions, PermissionCache,
+    PermissionUser, ProxyHeaderResolver, RememberMe, RolePreview, TokenStore,
+};
+use chrono::Utc;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use parking_lot::RwLock;
 use rocket::{
     fairing::{self, Fairing, Info},
     http::Status,
     outcome::{try_outcome, Outcome},
     request::{FromRequest, Request},
-    Build, Rocket, State,
+    Build, Orbit, Response, Rocket, State,
 };
 use rocket_sqlxsession::{SQLxSessionID, SQLxSessionStore};
-use sqlx::{pool::PoolConnection, postgres::PgPool};
-use std::marker::PhantomData;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::pool::PoolConnection;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// Locking discipline: the Session store's locks and every lock of this crate are parking_lot
+// locks, which block the thread and must never be held across an .await. Take them in order,
+// store.inner then a Session's Mutex, only within plain fns or a block that ends before the next
+// .await, copying out what is needed. Hooks like AuthEvents run after the locks are dropped.
+// parking_lot guards are not Send, so a guard held across an .await fails to compile within
+// from_request and within the futures_are_send test at the bottom of this file.
+
+/// A Result of AuthError with default return type of ()
+pub type Result<T = ()> = std::result::Result<T, AuthError>;
 
-pub use anyhow::Error;
-/// An anyhow::Result with default return type of ()
-pub type Result<T = ()> = std::result::Result<T, Error>;
+/// The sqlx Database users are loaded from, picked with the postgres, mysql or sqlite feature.
+/// When several are enabled sqlite is used over mysql and mysql over postgres.
+#[cfg(not(any(feature = "mysql", feature = "sqlite")))]
+pub type SqlxDatabase = sqlx::Postgres;
+#[cfg(all(feature = "mysql", not(feature = "sqlite")))]
+pub type SqlxDatabase = sqlx::MySql;
+#[cfg(feature = "sqlite")]
+pub type SqlxDatabase = sqlx::Sqlite;
+
+/// The connection pool of SqlxDatabase.
+pub type SqlxPool = sqlx::Pool<SqlxDatabase>;
 
 #[rocket::async_trait]
 pub trait SQLxSessionAuth<D> {
-    async fn load_user(userid: i64, pool: &mut PoolConnection<sqlx::Postgres>) -> Result<D>;
+    /// The type of the users id, like i64 or a Uuid. It is stored in the Session as json.
+    type Id: 'static + Clone + fmt::Debug + PartialEq + Serialize + DeserializeOwned + Send + Sync;
+
+    async fn load_user(userid: Self::Id, pool: &mut PoolConnection<SqlxDatabase>) -> Result<D>;
+
+    /// Like load_user but with details of the request the user is loaded for, this is what the
+    /// guards call. Defaults to ignoring ctx and calling load_user.
+    async fn load_user_ctx(
+        userid: Self::Id,
+        pool: &mut PoolConnection<SqlxDatabase>,
+        ctx: &LoadContext,
+    ) -> Result<D> {
+        let _ = ctx;
+        Self::load_user(userid, pool).await
+    }
+
     fn is_authenticated(&self) -> bool;
     fn is_active(&self) -> bool;
     fn is_anonymous(&self) -> bool;
+
+    /// The key the user and their permissions are cached under, next to the requests tenant.
+    /// Defaults to the id as json, None keeps the user out of every cache. Override this to keep
+    /// raw ids out of cache keys, for example by hashing them.
+    fn cache_key(userid: &Self::Id) -> Option<String> {
+        serde_json::to_string(userid).ok()
+    }
+}
+
+/// Details of the request a user is loaded for, handed to SQLxSessionAuth::load_user_ctx.
+/// They are copied out of the request so nothing borrows it across load_user_ctx's await.
+/// Loads outside of a request, like the anonymous refresh, get an empty LoadContext. Cached users
+/// are reused regardless of the context they were loaded with.
+#[derive(Clone, Debug, Default)]
+pub struct LoadContext {
+    pub remote: Option<SocketAddr>,
+    pub client_ip: Option<IpAddr>,
+    pub tenant: Option<String>,
+    /// The headers selected with SqlxSessionAuthFairing::with_context_headers, keyed by their
+    /// lowercase name.
+    pub headers: HashMap<String, String>,
+}
+
+impl LoadContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn from_request(request: &Request<'_>, tenant: Option<String>, headers: &[String]) -> Self {
+        Self {
+            remote: request.remote(),
+            client_ip: request.client_ip(),
+            tenant,
+            headers: headers
+                .iter()
+                .filter_map(|name| {
+                    let value = request.headers().get_one(name)?;
+                    Some((name.to_lowercase(), value.to_string()))
+                })
+                .collect(),
+        }
+    }
+
+    /// The value of a selected header, the name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+}
+
+/// What the guard does when loading the user fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadErrorAction {
+    /// Continue without a loaded user, this is the default.
+    Anonymous,
+    /// Fail the request with 403 Forbidden.
+    Forbidden,
+    /// Fail the request with 503 Service Unavailable.
+    Unavailable,
+    /// Try loading the user again following the pools RetryPolicy, continuing without
+    /// a user once the retries run out.
+    Retry,
+}
+
+/// How often and how fast errors classified as Retry are retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The amount of retries after the first failed load.
+    pub retries: u32,
+    /// The wait before the first retry, doubled for every retry after.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(1, Duration::from_millis(0))
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        Self { retries, backoff }
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.pow(retry.min(10)))
+            .unwrap_or(self.backoff)
+    }
+}
+
+/// How a guard loads the current user.
+#[derive(Clone, Copy, Debug, Default)]
+struct LoadOptions {
+    /// Database errors fail the request instead of continuing as anonymous.
+    strict: bool,
+    /// Skips the user cache, the freshly loaded user still gets cached and their cached
+    /// permissions are dropped.
+    fresh: bool,
+}
+
+/// True if the user to load does not exist.
+fn is_not_found(error: &AuthError) -> bool {
+    matches!(error.as_sqlx(), Some(sqlx::Error::RowNotFound))
+}
+
+/// True for sqlx errors other than the row not existing.
+fn is_database_error(error: &AuthError) -> bool {
+    !matches!(error.as_sqlx(), None | Some(sqlx::Error::RowNotFound))
+}
+
+/// A shared callback held by the pool.
+pub struct Callback<F: ?Sized>(pub Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// Classifies load_user errors into what the guard should do about them.
+pub type ErrorClassifier = Callback<dyn Fn(&AuthError) -> LoadErrorAction + Send + Sync>;
+
+/// Returns the tenant a request belongs to, for example from its subdomain or a header.
+pub type TenantResolver = Callback<dyn Fn(&Request<'_>) -> Option<String> + Send + Sync>;
+
+/// Transforms a freshly loaded user, for example decrypting fields.
+pub type LoadedHook<D> = Callback<dyn Fn(&mut D) + Send + Sync>;
+
+/// What the guard does when the user id stored within the Session can not be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnonymousPolicy {
+    /// Continue as the anonymous user, this is the default.
+    Fallback,
+    /// Fail the request with 400 Bad Request.
+    RejectOnCorrupt,
+    /// Continue without any user, not even the anonymous user.
+    AlwaysNone,
+}
+
+impl Default for AnonymousPolicy {
+    fn default() -> Self {
+        AnonymousPolicy::Fallback
+    }
+}
+
+/// Hooks called on login and logout, for example to write an audit log. Errors are ignored
+/// so a failing hook never breaks the login itself.
+pub trait AuthEvents<D>: Send + Sync
+where
+    D: SQLxSessionAuth<D>,
+{
+    fn on_login(&self, id: &D::Id) -> Result {
+        let _ = id;
+        Ok(())
+    }
+
+    fn on_logout(&self, id: &D::Id) -> Result {
+        let _ = id;
+        Ok(())
+    }
+}
+
+/// The AuthEvents registered on the pool.
+pub type AuthEventsHook<D> = Callback<dyn AuthEvents<D>>;
+
+/// The Session key the logged in users id is stored under by default.
+pub const DEFAULT_SESSION_KEY: &str = "user_auth_session_id";
+
+/// Loaded users by (tenant, cache key), shared by the pool and every SQLxAuth.
+type UserCache<D> = Arc<RwLock<HashMap<(Option<String>, String), (Instant, D)>>>;
+
+/// Drops the user from every tenants user cache along with their cached permissions.
+fn invalidate_cached<D>(
+    user_cache: &UserCache<D>,
+    permission_cache: Option<&PermissionCache>,
+    key: &str,
+) {
+    user_cache.write().retain(|(_, cache_key), _| cache_key != key);
+
+    if let Some(permission_cache) = permission_cache {
+        permission_cache.invalidate(key);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SQLxSessionAuthPool<D>
 where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
 {
-    pub client: Option<PgPool>,
-    pub anonymous_user_id: Option<i64>,
+    /// The pool users get loaded from, None reuses the Session store's pool under postgres.
+    pub client: Option<SqlxPool>,
+    pub anonymous_user_id: Option<D::Id>,
+    /// The Session key the logged in users id is stored under.
+    pub session_key: String,
+    /// Paths that always resolve as anonymous without loading the user.
+    pub skip_paths: GlobSet,
+    /// How often the cached anonymous user gets reloaded. None disables the anonymous cache.
+    pub anonymous_refresh: Option<Duration>,
+    /// Resolves the user id from a trusted proxies header instead of the Session.
+    pub proxy_resolver: Option<ProxyHeaderResolver>,
+    /// How long loaded users stay cached. None disables the user cache.
+    pub user_cache_ttl: Option<Duration>,
+    /// Decides what happens on load errors. None fails the request with 503 when no connection
+    /// could be acquired and treats every other error as Anonymous.
+    pub classify_error: Option<ErrorClassifier>,
+    /// Used when classify_error returns Retry.
+    pub retry_policy: RetryPolicy,
+    /// The most sessions a user may be logged in with, the oldest logins get removed on login_user.
+    /// Only sessions currently held in memory by the Session store are counted.
+    pub max_sessions_per_user: Option<usize>,
+    /// Picks the tenant of a request, users are then loaded from that tenants pool.
+    pub tenant_resolver: Option<TenantResolver>,
+    /// The database pool of every tenant.
+    pub tenant_pools: HashMap<String, SqlxPool>,
+    /// Runs once right after load_user and before the user is cached.
+    pub on_loaded: Option<LoadedHook<D>>,
+    /// Cookie attributes applied by login_user.
+    pub login_options: Option<LoginOptions>,
+    /// Called by login_user and logout_user.
+    pub events: Option<AuthEventsHook<D>>,
+    /// Used when the Session holds a user id that can not be read.
+    pub anonymous_policy: AnonymousPolicy,
+    /// Logs users back in from their remember me token once their Session is gone.
+    pub remember: Option<RememberMe>,
+    /// Caches permission lookups of CachedPermissions across requests.
+    pub permission_cache: Option<PermissionCache>,
+    /// The request headers copied into the LoadContext.
+    pub context_headers: Vec<String>,
+    user_cache: UserCache<D>,
+    user_cache_pruned: PruneTimer,
     phantom: PhantomData<D>,
 }
 
 impl<D> SQLxSessionAuthPool<D>
 where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
 {
-    pub fn new(client: Option<PgPool>, anonymous_user_id: Option<i64>) -> Self {
+    pub fn new(client: Option<SqlxPool>, anonymous_user_id: Option<D::Id>) -> Self {
         Self {
             client,
             anonymous_user_id,
+            session_key: DEFAULT_SESSION_KEY.to_string(),
+            skip_paths: GlobSet::empty(),
+            anonymous_refresh: None,
+            proxy_resolver: None,
+            user_cache_ttl: None,
+            classify_error: None,
+            retry_policy: RetryPolicy::default(),
+            max_sessions_per_user: None,
+            tenant_resolver: None,
+            tenant_pools: HashMap::new(),
+            on_loaded: None,
+            login_options: None,
+            events: None,
+            anonymous_policy: AnonymousPolicy::default(),
+            remember: None,
+            permission_cache: None,
+            context_headers: Vec::new(),
+            user_cache: Arc::new(RwLock::new(HashMap::new())),
+            user_cache_pruned: PruneTimer::new(),
             phantom: PhantomData,
         }
     }
+
+    /// Returns true when the path matches one of the skipped paths.
+    pub fn is_skipped(&self, path: &str) -> bool {
+        !self.skip_paths.is_empty() && self.skip_paths.is_match(path)
+    }
+
+    /// The pool users get loaded from, falls back to the Session store's pool.
+    #[cfg(not(any(feature = "mysql", feature = "sqlite")))]
+    pub fn connection_pool<'a>(&'a self, store: &'a SQLxSessionStore) -> Option<&'a SqlxPool> {
+        Some(self.client.as_ref().unwrap_or(&store.client))
+    }
+
+    /// The pool users get loaded from. The Session store only uses Postgres, so there is no
+    /// fallback pool and None is returned without a client.
+    #[cfg(any(feature = "mysql", feature = "sqlite"))]
+    pub fn connection_pool<'a>(&'a self, _store: &'a SQLxSessionStore) -> Option<&'a SqlxPool> {
+        self.client.as_ref()
+    }
+
+    /// Acquires a connection from the requests tenant pool, for the permission checks of guards.
+    /// Like loading the user this fails with 404 for an unknown tenant and 500 when there is no
+    /// pool, 503 when no connection could be acquired.
+    pub async fn request_connection(
+        &self,
+        request: &Request<'_>,
+    ) -> Outcome<PoolConnection<SqlxDatabase>, (Status, ()), ()> {
+        let store = try_outcome!(request.guard::<&State<SQLxSessionStore>>().await);
+        let tenant = self.tenant(request);
+
+        match self.tenant_pool(tenant.as_deref(), store) {
+            Some(db) => match db.acquire().await {
+                Ok(conn) => Outcome::Success(conn),
+                Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+            },
+            None if tenant.is_some() => Outcome::Failure((Status::NotFound, ())),
+            None => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+
+    /// Returns the tenant of the request, None when no tenant resolver is set.
+    pub fn tenant(&self, request: &Request<'_>) -> Option<String> {
+        self.tenant_resolver.as_ref().and_then(|resolve| (resolve.0)(request))
+    }
+
+    /// The pool to load the tenants users from. Requests without a tenant use the default pool,
+    /// None is returned for unknown tenants so they are never loaded from another tenants database.
+    pub fn tenant_pool<'a>(
+        &'a self,
+        tenant: Option<&str>,
+        store: &'a SQLxSessionStore,
+    ) -> Option<&'a SqlxPool> {
+        match tenant {
+            Some(tenant) => self.tenant_pools.get(tenant),
+            None => self.connection_pool(store),
+        }
+    }
+
+    /// True if the user is the anonymous user kept cached by the anonymous refresh task.
+    /// The anonymous user never expires from the cache, the refresh task replaces it instead.
+    fn is_refreshed_anonymous(&self, tenant: Option<&str>, userid: &D::Id) -> bool {
+        tenant.is_none()
+            && self.anonymous_refresh.is_some()
+            && self.anonymous_user_id.as_ref() == Some(userid)
+    }
+
+    /// Returns the cached user if it is still within the user caches ttl.
+    fn cached_user(&self, tenant: Option<&str>, userid: &D::Id) -> Option<D> {
+        let anonymous = self.is_refreshed_anonymous(tenant, userid);
+
+        if !anonymous && self.user_cache_ttl.is_none() {
+            return None;
+        }
+
+        let key = (tenant.map(String::from), D::cache_key(userid)?);
+        let cache = self.user_cache.read();
+
+        match cache.get(&key) {
+            Some((loaded, user)) if anonymous || self.is_fresh(loaded) => Some(user.clone()),
+            _ => None,
+        }
+    }
+
+    /// True while a user cached at loaded is within the user caches ttl.
+    fn is_fresh(&self, loaded: &Instant) -> bool {
+        match self.user_cache_ttl {
+            Some(ttl) => loaded.elapsed() < ttl,
+            None => false,
+        }
+    }
+
+    /// Inserts the user into the user cache, removing the expired users at most once per ttl.
+    fn cache_user(&self, tenant: Option<&str>, userid: &D::Id, user: &D) {
+        if !self.is_refreshed_anonymous(tenant, userid) && self.user_cache_ttl.is_none() {
+            return;
+        }
+
+        let key = match D::cache_key(userid) {
+            Some(key) => (tenant.map(String::from), key),
+            None => return,
+        };
+
+        let anonymous_key = match (self.anonymous_refresh, &self.anonymous_user_id) {
+            (Some(_), Some(id)) => D::cache_key(id).map(|key| (None, key)),
+            _ => None,
+        };
+
+        let mut cache = self.user_cache.write();
+        let prune = match self.user_cache_ttl {
+            Some(ttl) => self.user_cache_pruned.due(ttl),
+            None => false,
+        };
+
+        if prune {
+            cache.retain(|key, (loaded, _)| {
+                Some(key) == anonymous_key.as_ref() || self.is_fresh(loaded)
+            });
+        }
+
+        cache.insert(key, (Instant::now(), user.clone()));
+    }
+
+    /// Drops the users cached permissions within every tenant, like reload_user does.
+    fn invalidate_permissions(&self, userid: &D::Id) {
+        if let (Some(permission_cache), Some(key)) = (&self.permission_cache, D::cache_key(userid))
+        {
+            permission_cache.invalidate(&key);
+        }
+    }
+
+    /// The raw user id stored within the Session. A plain fn so the Session locks are always
+    /// released before the caller awaits.
+    fn stored_user_id(
+        &self,
+        store: &SQLxSessionStore,
+        session_id: &SQLxSessionID,
+    ) -> Option<String> {
+        let store_rg = store.inner.read();
+        let instance = store_rg.get(session_id.inner())?.lock();
+
+        instance.data.get(&self.session_key).cloned()
+    }
+
+    /// The user id of the requests remember me token, consuming the token.
+    async fn remembered_user(&self, request: &Request<'_>) -> Option<D::Id> {
+        let user_id = self
+            .remember
+            .as_ref()?
+            .consume(request.cookies(), &remember::cookie_name(&self.session_key))
+            .await?;
+        serde_json::from_str(&user_id).ok()
+    }
+
+    /// Removes the user from the user cache of every tenant so the next request reloads it,
+    /// along with their cached permissions.
+    pub fn invalidate_user(&self, userid: &D::Id) {
+        if let Some(key) = D::cache_key(userid) {
+            invalidate_cached(&self.user_cache, self.permission_cache.as_ref(), &key);
+        }
+    }
+
+    fn classify(&self, error: &AuthError) -> LoadErrorAction {
+        match &self.classify_error {
+            Some(classify) => (classify.0)(error),
+            None if matches!(error, AuthError::PoolAcquire(_)) => LoadErrorAction::Unavailable,
+            None => LoadErrorAction::Anonymous,
+        }
+    }
+
+    /// Loads the user through the user cache, the anonymous user included. Load errors are
+    /// turned into a failure Status or returned as the inner error to continue without a user,
+    /// depending on their LoadErrorAction.
+    async fn load(
+        &self,
+        db: &SqlxPool,
+        ctx: &LoadContext,
+        userid: &D::Id,
+        options: LoadOptions,
+    ) -> std::result::Result<Result<D>, Status> {
+        self.load_with(ctx.tenant.as_deref(), userid, options, || {
+            Self::load_uncached(self.on_loaded.as_ref(), db, ctx, userid)
+        })
+        .await
+    }
+
+    /// The user cache, retries and error handling of load around loader, which loads the user
+    /// from the database once per call.
+    async fn load_with<F, Fut>(
+        &self,
+        tenant: Option<&str>,
+        userid: &D::Id,
+        options: LoadOptions,
+        mut loader: F,
+    ) -> std::result::Result<Result<D>, Status>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<D>>,
+    {
+        if !options.fresh {
+            if let Some(user) = self.cached_user(tenant, userid) {
+                return Ok(Ok(user));
+            }
+        }
+
+        let mut retry = 0;
+
+        loop {
+            let error = match loader().await {
+                Ok(user) => {
+                    if options.fresh {
+                        self.invalidate_permissions(userid);
+                    }
+
+                    self.cache_user(tenant, userid, &user);
+                    return Ok(Ok(user));
+                }
+                Err(error) => error,
+            };
+
+            match self.classify(&error) {
+                LoadErrorAction::Forbidden => return Err(Status::Forbidden),
+                LoadErrorAction::Unavailable => return Err(Status::ServiceUnavailable),
+                LoadErrorAction::Retry if retry < self.retry_policy.retries => {
+                    tokio::time::sleep(self.retry_policy.delay(retry)).await;
+                    retry += 1;
+                }
+                LoadErrorAction::Anonymous | LoadErrorAction::Retry => {
+                    return if options.strict && is_database_error(&error) {
+                        Err(Status::ServiceUnavailable)
+                    } else {
+                        Ok(Err(error))
+                    };
+                }
+            }
+        }
+    }
+
+    /// Loads the user from the database running the on_loaded hook on it. Takes the hook so
+    /// SQLxAuth::reload_user loads the same way without the pool.
+    async fn load_uncached(
+        on_loaded: Option<&LoadedHook<D>>,
+        db: &SqlxPool,
+        ctx: &LoadContext,
+        userid: &D::Id,
+    ) -> Result<D> {
+        let mut guard = db.acquire().await.map_err(AuthError::PoolAcquire)?;
+        let mut user = D::load_user_ctx(userid.clone(), &mut guard, ctx).await?;
+
+        if let Some(on_loaded) = on_loaded {
+            (on_loaded.0)(&mut user);
+        }
+
+        Ok(user)
+    }
+
+    /// Reloads the cached anonymous user. On errors the previous cached user is kept.
+    /// This goes through the same load path as any other user, so load errors are classified
+    /// and retried the same way.
+    async fn refresh_anonymous(&self, store: &SQLxSessionStore) {
+        let userid = match &self.anonymous_user_id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let db = match self.connection_pool(store) {
+            Some(db) => db,
+            None => return,
+        };
+
+        let options = LoadOptions {
+            fresh: true,
+            ..LoadOptions::default()
+        };
+
+        let _ = self.load(db, &LoadContext::default(), userid, options).await;
+    }
+}
+
+/// The user loaded during the current request, shared by every auth guard of the request so the
+/// user is only loaded once. Kept within the requests local cache.
+pub(crate) struct RequestUser<D>(Arc<RwLock<Option<(Option<String>, D::Id, D)>>>)
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>;
+
+impl<D> RequestUser<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    fn get(&self, tenant: Option<&str>, userid: &D::Id) -> Option<D> {
+        match &*self.0.read() {
+            Some((cached_tenant, id, user))
+                if cached_tenant.as_deref() == tenant && id == userid =>
+            {
+                Some(user.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn set(&self, tenant: Option<&str>, userid: &D::Id, user: &D) {
+        *self.0.write() = Some((tenant.map(String::from), userid.clone(), user.clone()));
+    }
+
+    /// Replaces the user if it is the one loaded for the request.
+    fn update(&self, userid: &D::Id, user: &D) {
+        if let Some((_, id, cached)) = &mut *self.0.write() {
+            if id == userid {
+                *cached = user.clone();
+            }
+        }
+    }
+}
+
+impl<D> Default for RequestUser<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    fn default() -> Self {
+        Self(Arc::new(RwLock::new(None)))
+    }
+}
+
+impl<D> Clone for RequestUser<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<D> fmt::Debug for RequestUser<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestUser")
+    }
 }
 
 #[derive(Debug)]
 pub struct SQLxAuth<D>
 where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
 {
     pub current_user: Option<D>,
-    current_id: Option<i64>,
+    pub(crate) current_id: Option<D::Id>,
+    anonymous_user_id: Option<D::Id>,
+    load_error: Option<Arc<AuthError>>,
     session: SQLxSessionStore,
     session_id: SQLxSessionID,
+    max_sessions: Option<usize>,
+    session_key: String,
+    login_options: Option<LoginOptions>,
+    events: Option<AuthEventsHook<D>>,
+    on_loaded: Option<LoadedHook<D>>,
+    remember: Option<RememberMe>,
+    permission_cache: Option<PermissionCache>,
+    user_cache: UserCache<D>,
+    load_context: LoadContext,
+    pending_login: PendingLogin,
+    pending_forget: PendingForget,
+    pending_revoke: PendingRevoke,
+    request_user: RequestUser<D>,
 }
 
 impl<D> SQLxAuth<D>
 where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
 {
     /// Use this to check if the user is Authenticated
     pub fn is_authenticated(&self) -> bool {
@@ -83,143 +734,935 @@ where
         }
     }
 
-    /// Use this to Set the user login into the Session so it can auto login the user on request.
-    pub fn login_user(&self, id: i64) {
-        let store_rg = self.session.inner.read();
+    /// True only for an authenticated user that is not the pools anonymous user.
+    pub fn is_logged_in(&self) -> bool {
+        match &self.current_id {
+            Some(id) => self.anonymous_user_id.as_ref() != Some(id) && self.is_authenticated(),
+            None => false,
+        }
+    }
 
-        let mut instance = store_rg
-            .get(self.session_id.inner())
-            .expect("Session data unexpectedly missing")
-            .lock();
+    pub(crate) fn remember(&self) -> Option<&RememberMe> {
+        self.remember.as_ref()
+    }
 
-        let value = serde_json::to_string(&id).unwrap_or_else(|_| "".to_string());
-        if instance.data.get("user_auth_session_id") != Some(&value) {
-            instance.data.insert("user_auth_session_id".into(), value);
-        }
+    pub(crate) fn session_key(&self) -> &str {
+        &self.session_key
     }
 
-    /// Use this to remove the users login. Forcing them to login as anonymous.
-    pub fn logout_user(&self) {
-        let store_rg = self.session.inner.read();
+    /// The error loading the user failed with when the guard continued without a user.
+    /// None when the user loaded or there was no user to load.
+    pub fn load_error(&self) -> Option<&AuthError> {
+        self.load_error.as_deref()
+    }
 
-        let mut instance = store_rg
-            .get(self.session_id.inner())
-            .expect("Session data unexpectedly missing")
-            .lock();
+    /// The user id the guard resolved, also set when loading the user failed.
+    pub fn current_user_id(&self) -> Option<&D::Id> {
+        self.current_id.as_ref()
+    }
 
-        instance.data.remove("user_auth_session_id");
+    /// Use this to Set the user login into the Session so it can auto login the user on request.
+    /// If the pool limits the sessions per user the users oldest logins are removed to make room,
+    /// along with their remember me tokens once the response is sent. The fairings LoginOptions
+    /// are applied to the Session cookie when set.
+    /// Errors if the Session was removed from the store during the request.
+    pub fn login_user(&self, id: D::Id) -> Result {
+        match &self.login_options {
+            Some(options) => self.login_user_with_options(id, options.clone()),
+            None => self.store_login(id),
+        }
     }
-}
 
-#[rocket::async_trait]
-impl<'r, D> FromRequest<'r> for SQLxAuth<D>
-where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
-{
-    type Error = ();
+    /// Like login_user but with the given cookie attributes instead of the fairings.
+    /// A max_age also extends the server side session when it would expire sooner.
+    pub fn login_user_with_options(&self, id: D::Id, options: LoginOptions) -> Result {
+        self.store_login(id)?;
 
-    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
-        let store = try_outcome!(request.guard::<&State<SQLxSessionStore>>().await);
-        let authpool = try_outcome!(request.guard::<&State<SQLxSessionAuthPool<D>>>().await);
+        {
+            let store_rg = self.session.inner.read();
 
-        let session_id = request.local_cache(|| SQLxSessionID::new("".to_string()));
+            if let Some(session) = store_rg.get(self.session_id.inner()) {
+                let mut instance = session.lock();
 
-        let current_id = {
-            let store_ug = store.inner.read();
+                if let Some(expires) = options.extended_expiry(instance.expires) {
+                    instance.expires = expires;
+                }
+            }
+        }
 
-            if let Some(m) = store_ug.get(session_id.inner()) {
-                let inner = m.lock();
+        self.pending_login.set(options);
+        Ok(())
+    }
 
-                if let Some(data) = inner.data.get("user_auth_session_id") {
-                    let uid: Option<i64> = match serde_json::from_str(data).ok() {
-                        Some(i) => Some(i),
-                        None => authpool.anonymous_user_id,
-                    };
+    fn store_login(&self, id: D::Id) -> Result {
+        let value = serde_json::to_string(&id)?;
+        let login_at = serde_json::to_string(&Utc::now().timestamp_millis())?;
+        let login_at_key = self.login_at_key();
+        let store_rg = self.session.inner.read();
 
-                    uid
-                } else {
-                    authpool.anonymous_user_id
-                }
-            } else {
-                authpool.anonymous_user_id
+        {
+            let mut instance = store_rg
+                .get(self.session_id.inner())
+                .ok_or(AuthError::SessionMissing)?
+                .lock();
+
+            if instance.data.get(&self.session_key) != Some(&value) {
+                instance.data.insert(self.session_key.clone(), value.clone());
+                instance.data.insert(login_at_key.clone(), login_at);
             }
-        };
+        }
 
-        let current_user = {
-            match current_id {
-                None => None,
-                Some(uid) => {
-                    if let Some(client) = &authpool.client {
-                        let mut guard: PoolConnection<sqlx::Postgres> =
-                            client.acquire().await.unwrap();
+        if let Some(max) = self.max_sessions {
+            // Walks every session held in memory, so this costs more the more sessions are active.
+            let mut logins: Vec<(i64, String)> = store_rg
+                .iter()
+                .filter(|(session_id, _)| session_id.as_str() != self.session_id.inner())
+                .filter_map(|(session_id, session)| {
+                    let inner = session.lock();
 
-                        match D::load_user(uid, &mut guard).await {
-                            Ok(user) => Some(user),
-                            Err(_) => None,
-                        }
+                    if inner.data.get(&self.session_key) == Some(&value) {
+                        let login_at = inner
+                            .data
+                            .get(&login_at_key)
+                            .and_then(|at| serde_json::from_str(at).ok())
+                            .unwrap_or(0);
+
+                        Some((login_at, session_id.clone()))
                     } else {
-                        let mut guard: PoolConnection<sqlx::Postgres> =
-                            store.client.acquire().await.unwrap();
+                        None
+                    }
+                })
+                .collect();
 
-                        match D::load_user(uid, &mut guard).await {
-                            Ok(user) => Some(user),
-                            Err(_) => None,
-                        }
+            let evict = (logins.len() + 1).saturating_sub(max.max(1));
+            logins.sort();
+
+            for (_, session_id) in logins.iter().take(evict) {
+                if let Some(session) = store_rg.get(session_id) {
+                    let mut inner = session.lock();
+
+                    if let Some(token_hash) = inner.data.remove(&self.remember_key()) {
+                        self.pending_revoke.push(token_hash);
                     }
+
+                    self.clear_login(&mut inner.data);
                 }
             }
-        };
+        }
 
-        Outcome::Success(SQLxAuth {
-            current_id,
-            current_user,
-            session: store.inner().clone(),
-            session_id: session_id.clone(),
-        })
-    }
-}
+        drop(store_rg);
+        self.pending_forget.set(false);
 
-/// Fairing struct
-pub struct SqlxSessionAuthFairing<D>
-where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
-{
-    poll: Option<PgPool>,
-    anonymous_user_id: Option<i64>,
-    phantom: PhantomData<D>,
-}
+        if let Some(events) = &self.events {
+            let _ = events.0.on_login(&id);
+        }
 
-impl<D> Default for SqlxSessionAuthFairing<D>
-where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
-{
-    fn default() -> Self {
-        Self::new(None, None)
+        Ok(())
     }
-}
 
-impl<D> SqlxSessionAuthFairing<D>
+    /// Loads the current user again, for example after changing their permissions, so later checks
+    /// within the request see the change. Does nothing without a current id. The pools on_loaded
+    /// hook runs on the reloaded user like on any other load. This also replaces the user other
+    /// guards of the request get and drops their cached permissions, but the pools user cache is
+    /// left alone, use SQLxSessionAuthPool::invalidate_user to drop the cached user.
+    pub async fn reload_user(&mut self, pool: &SqlxPool) -> Result {
+        let userid = match self.current_id.clone() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let user = SQLxSessionAuthPool::load_uncached(
+            self.on_loaded.as_ref(),
+            pool,
+            &self.load_context,
+            &userid,
+        )
+        .await?;
+
+        let key = D::cache_key(&userid);
+
+        if let (Some(permission_cache), Some(key)) = (&self.permission_cache, key) {
+            permission_cache.invalidate(&key);
+        }
+
+        self.request_user.update(&userid, &user);
+        self.current_user = Some(user);
+        self.load_error = None;
+        Ok(())
+    }
+
+    /// Logs the user in and loads them right away, so the rest of the request already sees
+    /// the logged in user. If loading fails the login is kept but current_user is left empty.
+    pub async fn login_user_and_load(&mut self, id: D::Id, pool: &SqlxPool) -> Result {
+        self.login_user(id.clone())?;
+        self.current_id = Some(id);
+        self.current_user = None;
+        self.reload_user(pool).await
+    }
+
+    /// The loaded user.
+    pub fn user(&self) -> Option<&D> {
+        self.current_user.as_ref()
+    }
+
+    /// Mutable access to the loaded user. Changes only last for the current request
+    /// and are not saved.
+    pub fn user_mut(&mut self) -> Option<&mut D> {
+        self.current_user.as_mut()
+    }
+
+    /// Mutable access to the loaded user, the same as user_mut.
+    pub fn current_user_mut(&mut self) -> Option<&mut D> {
+        self.user_mut()
+    }
+
+    /// Starts previewing the site as if the user only held the roles permissions. The role is kept
+    /// within the Session until stop_preview is called. The guards and permission_user check the
+    /// previewed role, the user must return Some from HasPermission::as_roles.
+    /// Errors with SessionMissing if the Session is gone.
+    pub fn preview_role(&self, role: &str) -> Result {
+        let value = serde_json::to_string(role)?;
+
+        self.with_session_data(|data| {
+            data.insert(self.preview_role_key(), value);
+        })
+        .ok_or(AuthError::SessionMissing)
+    }
+
+    /// Ends the role preview. Errors with SessionMissing if the Session is gone.
+    pub fn stop_preview(&self) -> Result {
+        self.with_session_data(|data| {
+            data.remove(&self.preview_role_key());
+        })
+        .ok_or(AuthError::SessionMissing)
+    }
+
+    /// The role currently being previewed.
+    pub fn previewed_role(&self) -> Option<String> {
+        self.with_session_data(|data| {
+            data.get(&self.preview_role_key())
+                .and_then(|role| serde_json::from_str(role).ok())
+        })
+        .flatten()
+    }
+
+    /// The current user limited to the previewed roles permissions, None if no preview is active.
+    pub fn role_preview(&self) -> Option<RolePreview<'_, D>>
+    where
+        D: HasPermission,
+    {
+        let role = self.previewed_role()?;
+        self.current_user.as_ref().map(|user| RolePreview::new(user, role))
+    }
+
+    /// The current user with permission lookups going through the fairings PermissionCache,
+    /// None without a current user or without a PermissionCache.
+    pub fn cached_permissions(&self) -> Option<CachedPermissions<'_, D>>
+    where
+        D: HasPermission,
+    {
+        let cache = self.permission_cache.as_ref()?;
+        let user = self.current_user.as_ref()?;
+        let id = self.current_id.as_ref()?;
+
+        Some(cache.user(user, self.load_context.tenant.as_deref(), D::cache_key(id)?))
+    }
+
+    /// The Session key the login time is kept under, next to the session_key.
+    fn login_at_key(&self) -> String {
+        format!("{}_login_at", self.session_key)
+    }
+
+    /// The Session key the hash of the Sessions remember me token is kept under.
+    fn remember_key(&self) -> String {
+        format!("{}_remember", self.session_key)
+    }
+
+    /// The Session key the previewed role is kept under, next to the session_key.
+    fn preview_role_key(&self) -> String {
+        format!("{}_preview_role", self.session_key)
+    }
+
+    /// The current user as guards check their permissions, limited to the previewed role while
+    /// one is active, otherwise through the PermissionCache when the fairing has one.
+    /// None without a current user.
+    pub fn permission_user(&self) -> Option<PermissionUser<'_, D>>
+    where
+        D: HasPermission,
+    {
+        if let Some(preview) = self.role_preview() {
+            return Some(PermissionUser::Preview(preview));
+        }
+
+        match self.cached_permissions() {
+            Some(cached) => Some(PermissionUser::Cached(cached)),
+            None => self.current_user.as_ref().map(PermissionUser::User),
+        }
+    }
+
+    /// Removes the login, its login time and any role preview from a Sessions data, so nothing
+    /// of the login carries over to the next user of the Session. Returns the removed user id.
+    fn clear_login(&self, data: &mut HashMap<String, String>) -> Option<String> {
+        data.remove(&self.login_at_key());
+        data.remove(&self.remember_key());
+        data.remove(&self.preview_role_key());
+        data.remove(&self.session_key)
+    }
+
+    /// Records the hash of the Sessions remember me token, so evicting the Session revokes it.
+    /// A token recorded before is revoked.
+    pub(crate) fn record_remember_token(&self, token_hash: String) {
+        let replaced = self
+            .with_session_data(|data| data.insert(self.remember_key(), token_hash))
+            .flatten();
+
+        if let Some(replaced) = replaced {
+            self.pending_revoke.push(replaced);
+        }
+    }
+
+    /// Revokes the remember me tokens of the Sessions evicted during the request.
+    pub(crate) async fn revoke_evicted(&self) {
+        if let Some(remember) = &self.remember {
+            remember.revoke(self.pending_revoke.take()).await;
+        }
+    }
+
+    fn with_session_data<R>(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, String>) -> R,
+    ) -> Option<R> {
+        let store_rg = self.session.inner.read();
+        let mut instance = store_rg.get(self.session_id.inner())?.lock();
+
+        Some(f(&mut instance.data))
+    }
+
+    /// Gives direct access to the Session store the auth layer uses.
+    /// This is an escape hatch, writing the auth key directly bypasses login_user's safeguards.
+    pub fn session(&self) -> &SQLxSessionStore {
+        &self.session
+    }
+
+    /// The SessionID of the current request within the Session store.
+    pub fn session_id(&self) -> &SQLxSessionID {
+        &self.session_id
+    }
+
+    /// Removes the users login from every session, for example after a password reset, returning
+    /// how many sessions were logged out. Like max sessions per user this only reaches sessions
+    /// currently held in memory by the Session store, and walks all of them, sessions only
+    /// persisted to the database keep their login. The users remember me tokens are revoked and
+    /// the user is dropped from the user and permission caches as well.
+    pub async fn logout_user_everywhere(&self, user_id: &D::Id) -> Result<usize> {
+        let value = serde_json::to_string(user_id)?;
+        let mut logged_out = 0;
+
+        if let Some(remember) = &self.remember {
+            remember.store.0.remove_user(&value).await?;
+        }
+
+        {
+            let store_rg = self.session.inner.read();
+
+            for session in store_rg.values() {
+                let mut inner = session.lock();
+
+                if inner.data.get(&self.session_key) == Some(&value) {
+                    self.clear_login(&mut inner.data);
+                    logged_out += 1;
+                }
+            }
+        }
+
+        if let Some(key) = D::cache_key(user_id) {
+            invalidate_cached(&self.user_cache, self.permission_cache.as_ref(), &key);
+        }
+
+        if let (Some(events), true) = (&self.events, logged_out > 0) {
+            let _ = events.0.on_logout(user_id);
+        }
+
+        Ok(logged_out)
+    }
+
+    /// Use this to remove the users login. Forcing them to login as anonymous.
+    /// With remember me enabled the requests remember me token is revoked once the response is
+    /// sent, so it can not log the user back in. Errors if the Session was removed from the store
+    /// during the request.
+    pub fn logout_user(&self) -> Result {
+        let logged_out = {
+            let store_rg = self.session.inner.read();
+
+            let mut instance = store_rg
+                .get(self.session_id.inner())
+                .ok_or(AuthError::SessionMissing)?
+                .lock();
+
+            self.clear_login(&mut instance.data)
+        };
+
+        if self.remember.is_some() {
+            self.pending_forget.set(true);
+        }
+
+        if let Some(events) = &self.events {
+            if let Some(id) = logged_out.and_then(|id| serde_json::from_str(&id).ok()) {
+                let _ = events.0.on_logout(&id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<D> SQLxAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    /// An auth outside of any request, with default pool settings and no Session.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn detached(
+        current_id: Option<D::Id>,
+        current_user: Option<D>,
+        session: SQLxSessionStore,
+    ) -> Self {
+        Self::build(
+            &SQLxSessionAuthPool::new(None, None),
+            &session,
+            None,
+            current_id,
+            current_user,
+            None,
+            LoadContext::default(),
+        )
+    }
+
+    /// Builds the auth from the pools settings. Within a request the Session id, pending login,
+    /// pending forget and request user come from the requests local cache.
+    fn build(
+        authpool: &SQLxSessionAuthPool<D>,
+        session: &SQLxSessionStore,
+        request: Option<&Request<'_>>,
+        current_id: Option<D::Id>,
+        current_user: Option<D>,
+        load_error: Option<Arc<AuthError>>,
+        load_context: LoadContext,
+    ) -> Self {
+        let session_id = match request {
+            Some(request) => request
+                .local_cache(|| SQLxSessionID::new("".to_string()))
+                .clone(),
+            None => SQLxSessionID::new("".to_string()),
+        };
+
+        SQLxAuth {
+            current_id,
+            current_user,
+            anonymous_user_id: authpool.anonymous_user_id.clone(),
+            load_error,
+            session: session.clone(),
+            session_id,
+            max_sessions: authpool.max_sessions_per_user,
+            session_key: authpool.session_key.clone(),
+            login_options: authpool.login_options.clone(),
+            events: authpool.events.clone(),
+            on_loaded: authpool.on_loaded.clone(),
+            remember: authpool.remember.clone(),
+            permission_cache: authpool.permission_cache.clone(),
+            user_cache: authpool.user_cache.clone(),
+            load_context,
+            pending_login: request
+                .map(|request| request.local_cache(PendingLogin::default).clone())
+                .unwrap_or_default(),
+            pending_forget: request
+                .map(|request| request.local_cache(PendingForget::default).clone())
+                .unwrap_or_default(),
+            pending_revoke: request
+                .map(|request| request.local_cache(PendingRevoke::default).clone())
+                .unwrap_or_default(),
+            request_user: request
+                .map(|request| request.local_cache(RequestUser::<D>::default).clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Resolves the current user of the request, shared by all of the auth guards.
+    async fn resolve(
+        request: &Request<'_>,
+        options: LoadOptions,
+    ) -> Outcome<Self, (Status, ()), ()> {
+        let store = try_outcome!(request.guard::<&State<SQLxSessionStore>>().await);
+        let authpool = try_outcome!(request.guard::<&State<SQLxSessionAuthPool<D>>>().await);
+
+        let session_id = request.local_cache(|| SQLxSessionID::new("".to_string()));
+
+        if authpool.is_skipped(request.uri().path().as_str()) {
+            return Outcome::Success(SQLxAuth::build(
+                authpool,
+                store,
+                Some(request),
+                None,
+                None,
+                None,
+                LoadContext::default(),
+            ));
+        }
+
+        let mut remembered = false;
+
+        let current_id = match &authpool.proxy_resolver {
+            Some(resolver) if resolver.is_trusted(request) => resolver
+                .user_id(request)
+                .or_else(|| authpool.anonymous_user_id.clone()),
+            // The Session fairing set no id, so there is no Session to look up or log in to.
+            _ if session_id.inner().is_empty() => authpool.anonymous_user_id.clone(),
+            _ => {
+                match authpool.stored_user_id(store, session_id) {
+                    Some(data) => match serde_json::from_str(&data).ok() {
+                        Some(i) => Some(i),
+                        None => match authpool.anonymous_policy {
+                            AnonymousPolicy::Fallback => authpool.anonymous_user_id.clone(),
+                            AnonymousPolicy::RejectOnCorrupt => {
+                                return Outcome::Failure((Status::BadRequest, ()));
+                            }
+                            AnonymousPolicy::AlwaysNone => None,
+                        },
+                    },
+                    None => match authpool.remembered_user(request).await {
+                        Some(id) => {
+                            remembered = true;
+                            Some(id)
+                        }
+                        None => authpool.anonymous_user_id.clone(),
+                    },
+                }
+            }
+        };
+
+        let request_user = request.local_cache(RequestUser::<D>::default);
+        let ctx = LoadContext::from_request(
+            request,
+            authpool.tenant(request),
+            &authpool.context_headers,
+        );
+
+        let loaded = match &current_id {
+            None => Ok((None, None)),
+            Some(uid) => {
+                let tenant = ctx.tenant.as_deref();
+
+                match authpool.tenant_pool(tenant, store) {
+                    Some(db) => match request_user.get(tenant, uid) {
+                        Some(user) if !options.fresh => Ok((Some(user), None)),
+                        // Failed loads are not kept so a later StrictAuth still sees the error.
+                        _ => match authpool.load(db, &ctx, uid, options).await {
+                            Ok(Ok(user)) => {
+                                request_user.set(tenant, uid, &user);
+                                Ok((Some(user), None))
+                            }
+                            Ok(Err(error)) => Ok((None, Some(Arc::new(error)))),
+                            Err(status) => Err(status),
+                        },
+                    },
+                    None if tenant.is_some() => Err(Status::NotFound),
+                    None => Err(Status::InternalServerError),
+                }
+            }
+        };
+
+        // A remembered user that did not load keeps their token for the next request, unless
+        // they no longer exist.
+        if let (true, Some(remember), Some(id)) = (remembered, &authpool.remember, &current_id) {
+            let name = remember::cookie_name(&authpool.session_key);
+
+            match &loaded {
+                Ok((Some(_), _)) => {}
+                Ok((_, Some(error))) if is_not_found(error) => {
+                    remember.forget(request.cookies(), &name).await;
+                }
+                _ => {
+                    let _ = remember.restore(request.cookies(), &name, id).await;
+                }
+            }
+        }
+
+        let (current_user, load_error) = match loaded {
+            Ok(loaded) => loaded,
+            Err(status) => return Outcome::Failure((status, ())),
+        };
+
+        let auth = SQLxAuth::build(
+            authpool,
+            store,
+            Some(request),
+            current_id,
+            current_user,
+            load_error,
+            ctx,
+        );
+
+        // Logs the loaded remembered user back into the Session and rotates their token.
+        if let (true, Some(remember), Some(id)) = (remembered, &auth.remember, &auth.current_id) {
+            if auth.current_user.is_some() && auth.login_user(id.clone()).is_ok() {
+                auth.revoke_evicted().await;
+
+                let name = remember::cookie_name(&auth.session_key);
+
+                if let Ok(token_hash) = remember.issue(request.cookies(), &name, id).await {
+                    auth.record_remember_token(token_hash);
+                }
+            }
+        }
+
+        Outcome::Success(auth)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, D> FromRequest<'r> for SQLxAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
+        SQLxAuth::resolve(request, LoadOptions::default()).await
+    }
+}
+
+/// Like SQLxAuth but fails closed. Database errors while loading the user fail the request
+/// with 503 instead of continuing as anonymous, while users that are not found still continue
+/// without a user. load_user must return the sqlx::Error, as AuthError::Sqlx or within
+/// AuthError::Load, for the two to be told apart.
+#[derive(Debug)]
+pub struct StrictAuth<D>(pub SQLxAuth<D>)
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>;
+
+impl<D> Deref for StrictAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    type Target = SQLxAuth<D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<D> DerefMut for StrictAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, D> FromRequest<'r> for StrictAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let options = LoadOptions {
+            strict: true,
+            ..LoadOptions::default()
+        };
+
+        SQLxAuth::resolve(request, options).await.map(StrictAuth)
+    }
+}
+
+/// Like SQLxAuth but always loads the user from the database, bypassing the user cache.
+/// The freshly loaded user replaces the cached one and their cached permissions are dropped.
+/// Use it on routes that must see their own writes, like a settings page right after an edit.
+#[derive(Debug)]
+pub struct FreshAuth<D>(pub SQLxAuth<D>)
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>;
+
+impl<D> Deref for FreshAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    type Target = SQLxAuth<D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<D> DerefMut for FreshAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, D> FromRequest<'r> for FreshAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let options = LoadOptions {
+            fresh: true,
+            ..LoadOptions::default()
+        };
+
+        SQLxAuth::resolve(request, options).await.map(FreshAuth)
+    }
+}
+
+/// Guard for routes that need a logged in user. Requests without an authenticated user fail
+/// with 401 Unauthorized.
+#[derive(Debug)]
+pub struct AuthedUser<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    pub user: D,
+    /// The auth the user was loaded by, for logging out and the Session.
+    pub auth: SQLxAuth<D>,
+}
+
+#[rocket::async_trait]
+impl<'r, D> FromRequest<'r> for AuthedUser<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let auth = try_outcome!(SQLxAuth::resolve(request, LoadOptions::default()).await);
+
+        match auth.current_user.clone() {
+            Some(user) if user.is_authenticated() => Outcome::Success(AuthedUser { user, auth }),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Fairing struct
+pub struct SqlxSessionAuthFairing<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    poll: Option<SqlxPool>,
+    anonymous_user_id: Option<D::Id>,
+    session_key: String,
+    skip_paths: Vec<String>,
+    anonymous_refresh: Option<Duration>,
+    proxy_resolver: Option<ProxyHeaderResolver>,
+    user_cache_ttl: Option<Duration>,
+    classify_error: Option<ErrorClassifier>,
+    retry_policy: RetryPolicy,
+    max_sessions_per_user: Option<usize>,
+    tenant_resolver: Option<TenantResolver>,
+    tenant_pools: HashMap<String, SqlxPool>,
+    on_loaded: Option<LoadedHook<D>>,
+    login_options: Option<LoginOptions>,
+    events: Option<AuthEventsHook<D>>,
+    anonymous_policy: AnonymousPolicy,
+    remember: Option<RememberMe>,
+    permission_cache: Option<PermissionCache>,
+    context_headers: Vec<String>,
+    phantom: PhantomData<D>,
+}
+
+impl<D> Default for SqlxSessionAuthFairing<D>
 where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
 {
-    pub fn new(poll: Option<PgPool>, anonymous_user_id: Option<i64>) -> Self {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl<D> SqlxSessionAuthFairing<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    /// Users are loaded from poll, or from the Session store's pool when poll is None.
+    pub fn new(poll: Option<SqlxPool>, anonymous_user_id: Option<D::Id>) -> Self {
+        Self::new_with_key(poll, anonymous_user_id, DEFAULT_SESSION_KEY)
+    }
+
+    /// Loads users from the same pool the Session store uses, for single database setups.
+    #[cfg(not(any(feature = "mysql", feature = "sqlite")))]
+    pub fn new_with_session_pool(anonymous_user_id: Option<D::Id>) -> Self {
+        Self::new(None, anonymous_user_id)
+    }
+
+    /// Stores the logged in users id under key within the Session. Give each auth domain
+    /// sharing a Session store its own key so their logins do not collide.
+    pub fn new_with_key(
+        poll: Option<SqlxPool>,
+        anonymous_user_id: Option<D::Id>,
+        key: &str,
+    ) -> Self {
         Self {
             poll,
             anonymous_user_id,
+            session_key: key.to_string(),
+            skip_paths: Vec::new(),
+            anonymous_refresh: None,
+            proxy_resolver: None,
+            user_cache_ttl: None,
+            classify_error: None,
+            retry_policy: RetryPolicy::default(),
+            max_sessions_per_user: None,
+            tenant_resolver: None,
+            tenant_pools: HashMap::new(),
+            on_loaded: None,
+            login_options: None,
+            events: None,
+            anonymous_policy: AnonymousPolicy::default(),
+            remember: None,
+            permission_cache: None,
+            context_headers: Vec::new(),
             phantom: PhantomData,
         }
     }
+
+    /// Paths that should never load the user, Accepts exact paths or globs like "/static/**".
+    /// Invalid globs will fail Rocket's ignition.
+    pub fn with_skip_paths(mut self, paths: &[&str]) -> Self {
+        self.skip_paths.extend(paths.iter().map(|path| path.to_string()));
+        self
+    }
+
+    /// Caches the anonymous user and reloads it in the background every interval.
+    /// The refresh task stops when Rocket shuts down.
+    pub fn with_anonymous_refresh(mut self, interval: Duration) -> Self {
+        self.anonymous_refresh = Some(interval);
+        self
+    }
+
+    /// Loads users from a trusted proxies header. The header is ignored on requests
+    /// from anyone else.
+    pub fn with_proxy_resolver(mut self, resolver: ProxyHeaderResolver) -> Self {
+        self.proxy_resolver = Some(resolver);
+        self
+    }
+
+    /// Caches loaded users for ttl, keyed by SQLxSessionAuth::cache_key.
+    pub fn with_user_cache(mut self, ttl: Duration) -> Self {
+        self.user_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets how load errors are handled. By default failing to acquire a connection fails with 503
+    /// and load_user errors continue as Anonymous.
+    pub fn with_error_classifier<F>(mut self, classify: F) -> Self
+    where
+        F: Fn(&AuthError) -> LoadErrorAction + Send + Sync + 'static,
+    {
+        self.classify_error = Some(Callback(Arc::new(classify)));
+        self
+    }
+
+    /// Sets how errors classified as Retry are retried. Keep retries low so a database
+    /// outage is not amplified by every request retrying.
+    pub fn with_retry_policy(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retry_policy = RetryPolicy::new(retries, backoff);
+        self
+    }
+
+    /// Limits how many sessions a user can be logged into at once, removing the login from
+    /// their oldest sessions when exceeded. This walks every in memory session on login. The
+    /// remember me tokens of removed sessions are revoked so they do not log the session back in.
+    pub fn with_max_sessions_per_user(mut self, max: usize) -> Self {
+        self.max_sessions_per_user = Some(max);
+        self
+    }
+
+    /// Loads users from the pool of the tenant the resolver returns. Requests without a tenant use
+    /// the default pool and requests for unknown tenants fail with 404.
+    pub fn with_tenants<F>(mut self, resolver: F, pools: HashMap<String, SqlxPool>) -> Self
+    where
+        F: Fn(&Request<'_>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.tenant_resolver = Some(Callback(Arc::new(resolver)));
+        self.tenant_pools = pools;
+        self
+    }
+
+    /// Runs the hook on every user right after it is loaded and before it is cached,
+    /// so cached users are already transformed.
+    pub fn with_on_loaded<F>(mut self, on_loaded: F) -> Self
+    where
+        F: Fn(&mut D) + Send + Sync + 'static,
+    {
+        self.on_loaded = Some(Callback(Arc::new(on_loaded)));
+        self
+    }
+
+    /// Cookie attributes login_user applies to the Session cookie. The attributes are written
+    /// onto the cookie rocket_sqlxsession sets, any attribute left None keeps its setting.
+    pub fn with_login_options(mut self, options: LoginOptions) -> Self {
+        self.login_options = Some(options);
+        self
+    }
+
+    /// Sets what happens when the user id within the Session can not be read, by default the
+    /// request continues as the anonymous user.
+    pub fn with_anonymous_policy(mut self, policy: AnonymousPolicy) -> Self {
+        self.anonymous_policy = policy;
+        self
+    }
+
+    /// Enables remember me logins with login_user_persistent, tokens are kept in store and last
+    /// for lifespan after their last use.
+    pub fn with_remember_me(
+        mut self,
+        store: impl TokenStore + 'static,
+        lifespan: chrono::Duration,
+    ) -> Self {
+        self.remember = Some(RememberMe {
+            store: Callback(Arc::new(store)),
+            lifespan,
+        });
+        self
+    }
+
+    /// Caches permission lookups made through SQLxAuth::cached_permissions.
+    pub fn with_permission_cache(mut self, cache: PermissionCache) -> Self {
+        self.permission_cache = Some(cache);
+        self
+    }
+
+    /// The request headers copied into the LoadContext handed to load_user_ctx, like
+    /// "Accept-Language".
+    pub fn with_context_headers(mut self, headers: &[&str]) -> Self {
+        self.context_headers.extend(headers.iter().map(|header| header.to_string()));
+        self
+    }
+
+    /// Registers hooks called whenever a user logs in or out.
+    pub fn with_events(mut self, events: impl AuthEvents<D> + 'static) -> Self {
+        self.events = Some(Callback(Arc::new(events)));
+        self
+    }
 }
 
 #[rocket::async_trait]
 impl<D> Fairing for SqlxSessionAuthFairing<D>
 where
-    D: 'static + Sync + Send + SQLxSessionAuth<D>,
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
 {
     fn info(&self) -> Info {
         Info {
             name: "SQLxSessionAuth",
-            kind: fairing::Kind::Ignite,
+            kind: fairing::Kind::Ignite | fairing::Kind::Liftoff | fairing::Kind::Response,
         }
     }
 
@@ -227,9 +1670,553 @@ where
         &self,
         rocket: Rocket<Build>,
     ) -> std::result::Result<Rocket<Build>, Rocket<Build>> {
-        Ok(rocket.manage(SQLxSessionAuthPool::<D>::new(
-            self.poll.clone(),
-            self.anonymous_user_id,
-        )))
+        // Only Postgres can fall back to the Session store's pool.
+        if cfg!(any(feature = "mysql", feature = "sqlite")) && self.poll.is_none() {
+            rocket::error!("SqlxSessionAuthFairing needs a pool when not using postgres.");
+            return Err(rocket);
+        }
+
+        let mut builder = GlobSetBuilder::new();
+
+        for path in &self.skip_paths {
+            match Glob::new(path) {
+                Ok(glob) => {
+                    builder.add(glob);
+                }
+                Err(e) => {
+                    rocket::error!("SqlxSessionAuthFairing skip path {:?} is invalid: {}", path, e);
+                    return Err(rocket);
+                }
+            }
+        }
+
+        let skip_paths = match builder.build() {
+            Ok(set) => set,
+            Err(e) => {
+                rocket::error!("SqlxSessionAuthFairing skip paths are invalid: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let mut pool =
+            SQLxSessionAuthPool::<D>::new(self.poll.clone(), self.anonymous_user_id.clone());
+        pool.session_key = self.session_key.clone();
+        pool.skip_paths = skip_paths;
+        pool.anonymous_refresh = self.anonymous_refresh;
+        pool.proxy_resolver = self.proxy_resolver.clone();
+        pool.user_cache_ttl = self.user_cache_ttl;
+        pool.classify_error = self.classify_error.clone();
+        pool.retry_policy = self.retry_policy;
+        pool.max_sessions_per_user = self.max_sessions_per_user;
+        pool.tenant_resolver = self.tenant_resolver.clone();
+        pool.tenant_pools = self.tenant_pools.clone();
+        pool.on_loaded = self.on_loaded.clone();
+        pool.login_options = self.login_options.clone();
+        pool.events = self.events.clone();
+        pool.anonymous_policy = self.anonymous_policy;
+        pool.remember = self.remember.clone();
+        pool.permission_cache = self.permission_cache.clone();
+        pool.context_headers = self.context_headers.clone();
+
+        Ok(rocket.manage(pool))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let interval = match self.anonymous_refresh {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        let (authpool, store) = match (
+            rocket.state::<SQLxSessionAuthPool<D>>(),
+            rocket.state::<SQLxSessionStore>(),
+        ) {
+            (Some(authpool), Some(store)) => (authpool.clone(), store.clone()),
+            _ => return,
+        };
+
+        let shutdown = rocket.shutdown();
+
+        tokio::spawn(async move {
+            tokio::pin!(shutdown);
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = ticker.tick() => authpool.refresh_anonymous(&store).await,
+                }
+            }
+        });
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, _: &mut Response<'r>) {
+        let forget = request.local_cache(PendingForget::default).take();
+        let revoke = request.local_cache(PendingRevoke::default).take();
+
+        if let Some(authpool) = request.rocket().state::<SQLxSessionAuthPool<D>>() {
+            if let Some(remember) = &authpool.remember {
+                if forget {
+                    let name = remember::cookie_name(&authpool.session_key);
+                    remember.forget(request.cookies(), &name).await;
+                }
+
+                remember.revoke(revoke).await;
+            }
+        }
+
+        let options = match request.local_cache(PendingLogin::default).take() {
+            Some(options) => options,
+            None => return,
+        };
+
+        if let Some(store) = request.rocket().state::<SQLxSessionStore>() {
+            let session_id = request.local_cache(|| SQLxSessionID::new("".to_string()));
+
+            options.apply(request.cookies(), &store.config, session_id.inner());
+        }
+    }
+}
+
+#[cfg(all(test, not(any(feature = "mysql", feature = "sqlite"))))]
+mod tests {
+    use super::*;
+    use crate::MemoryTokenStore;
+    use parking_lot::Mutex;
+    use rocket::local::asynchronous::Client;
+    use rocket_sqlxsession::{SQLxSessionData, SqlxSessionConfig};
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    };
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        id: i64,
+        authenticated: bool,
+    }
+
+    #[rocket::async_trait]
+    impl SQLxSessionAuth<User> for User {
+        type Id = i64;
+
+        async fn load_user(userid: i64, _pool: &mut PoolConnection<SqlxDatabase>) -> Result<User> {
+            Ok(User {
+                id: userid,
+                authenticated: true,
+            })
+        }
+
+        fn is_authenticated(&self) -> bool {
+            self.authenticated
+        }
+
+        fn is_active(&self) -> bool {
+            self.authenticated
+        }
+
+        fn is_anonymous(&self) -> bool {
+            !self.authenticated
+        }
+    }
+
+    #[rocket::async_trait]
+    impl HasPermission for User {
+        async fn has(
+            &self,
+            _perm: &str,
+            _pool: &Option<&mut PoolConnection<SqlxDatabase>>,
+        ) -> bool {
+            self.authenticated
+        }
+    }
+
+    fn user(id: i64) -> User {
+        User {
+            id,
+            authenticated: true,
+        }
+    }
+
+    /// A pool that never connects, must be made within a tokio runtime.
+    fn lazy_pool() -> SqlxPool {
+        PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/test")
+            .unwrap()
+    }
+
+    fn store() -> SQLxSessionStore {
+        SQLxSessionStore::new(lazy_pool(), SqlxSessionConfig::default())
+    }
+
+    fn add_session(store: &SQLxSessionStore, session_id: &str) {
+        let expires = Utc::now() + chrono::Duration::hours(1);
+
+        store.inner.write().insert(
+            session_id.to_string(),
+            Mutex::new(SQLxSessionData {
+                id: session_id.to_string(),
+                data: HashMap::new(),
+                expires,
+                autoremove: expires,
+                destroy: false,
+            }),
+        );
+    }
+
+    fn session_value(store: &SQLxSessionStore, session_id: &str, key: &str) -> Option<String> {
+        let store_rg = store.inner.read();
+        let instance = store_rg.get(session_id)?.lock();
+
+        instance.data.get(key).cloned()
+    }
+
+    fn auth(store: &SQLxSessionStore, session_id: &str) -> SQLxAuth<User> {
+        let mut auth = SQLxAuth::detached(None, None, store.clone());
+        auth.session_id = SQLxSessionID::new(session_id.to_string());
+        auth
+    }
+
+    fn remember_me() -> RememberMe {
+        RememberMe {
+            store: Callback(Arc::new(MemoryTokenStore::new())),
+            lifespan: chrono::Duration::days(1),
+        }
+    }
+
+    fn assert_send<T: Send>(_: T) {}
+
+    const STRICT: LoadOptions = LoadOptions {
+        strict: true,
+        fresh: false,
+    };
+
+    fn timed_out() -> Result<User> {
+        Err(AuthError::Sqlx(sqlx::Error::PoolTimedOut))
+    }
+
+    fn not_found() -> Result<User> {
+        Err(AuthError::Sqlx(sqlx::Error::RowNotFound))
+    }
+
+    /// Loads userid through load_with with a loader returning result. Gives back if a user loaded
+    /// or the failure Status, along with how often the loader ran.
+    async fn load_counted(
+        pool: &SQLxSessionAuthPool<User>,
+        userid: i64,
+        options: LoadOptions,
+        result: impl Fn() -> Result<User>,
+    ) -> (std::result::Result<bool, Status>, usize) {
+        let mut calls = 0;
+        let outcome = pool
+            .load_with(None, &userid, options, || {
+                calls += 1;
+                let result = result();
+                async move { result }
+            })
+            .await;
+
+        (outcome.map(|user| user.is_ok()), calls)
+    }
+
+    /// Fails to compile when one of the async fns holds a lock across an .await. The futures are
+    /// never polled.
+    #[tokio::test]
+    async fn futures_are_send() {
+        let store = store();
+        let pool = lazy_pool();
+        let tokens = MemoryTokenStore::new();
+        let client = Client::untracked(rocket::build()).await.unwrap();
+        let request = client.get("/");
+        let jar = request.inner().cookies();
+        let mut auth = auth(&store, "s");
+
+        assert_send(auth.reload_user(&pool));
+        assert_send(auth.login_user_and_load(1, &pool));
+        assert_send(auth.login_user_persistent(1, jar));
+        assert_send(auth.logout_user_persistent(jar));
+        assert_send(auth.login_with_magic_link(&tokens, "token"));
+        assert_send(auth.logout_user_everywhere(&1));
+    }
+
+    #[tokio::test]
+    async fn logout_user_forgets_the_remember_token() {
+        let store = store();
+        add_session(&store, "s");
+
+        let mut auth = auth(&store, "s");
+        auth.remember = Some(remember_me());
+
+        auth.login_user(1).unwrap();
+        assert!(!auth.pending_forget.take());
+
+        auth.logout_user().unwrap();
+        assert!(auth.pending_forget.take());
+        assert_eq!(session_value(&store, "s", DEFAULT_SESSION_KEY), None);
+    }
+
+    #[tokio::test]
+    async fn logout_user_without_remember_me_forgets_nothing() {
+        let store = store();
+        add_session(&store, "s");
+
+        let auth = auth(&store, "s");
+
+        auth.login_user(1).unwrap();
+        auth.logout_user().unwrap();
+        assert!(!auth.pending_forget.take());
+    }
+
+    #[tokio::test]
+    async fn logout_user_everywhere_reaches_every_session() {
+        let store = store();
+
+        for session_id in &["a", "b", "c"] {
+            add_session(&store, session_id);
+        }
+
+        auth(&store, "a").login_user(1).unwrap();
+        auth(&store, "b").login_user(1).unwrap();
+        auth(&store, "c").login_user(2).unwrap();
+
+        let mut pool = SQLxSessionAuthPool::<User>::new(None, None);
+        pool.user_cache_ttl = Some(Duration::from_secs(60));
+        pool.cache_user(None, &1, &user(1));
+
+        let remember = remember_me();
+        let expires = Utc::now() + chrono::Duration::days(1);
+        remember.store.0.insert("token", "1", expires).await.unwrap();
+
+        let mut auth = auth(&store, "c");
+        auth.user_cache = pool.user_cache.clone();
+        auth.remember = Some(remember.clone());
+
+        assert_eq!(auth.logout_user_everywhere(&1).await.unwrap(), 2);
+        assert_eq!(session_value(&store, "a", DEFAULT_SESSION_KEY), None);
+        assert_eq!(session_value(&store, "b", DEFAULT_SESSION_KEY), None);
+        assert_eq!(session_value(&store, "c", DEFAULT_SESSION_KEY), Some("2".to_string()));
+        assert_eq!(pool.cached_user(None, &1), None);
+        assert_eq!(remember.store.0.consume("token", Utc::now()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn logins_over_the_session_cap_evict_the_oldest() {
+        let store = store();
+
+        for session_id in &["a", "b", "c", "other"] {
+            add_session(&store, session_id);
+        }
+
+        auth(&store, "other").login_user(8).unwrap();
+
+        let remember = remember_me();
+        let expires = Utc::now() + chrono::Duration::days(1);
+
+        for session_id in &["a", "b", "c"] {
+            let mut auth = auth(&store, session_id);
+            auth.max_sessions = Some(2);
+            auth.remember = Some(remember.clone());
+            auth.login_user(7).unwrap();
+            auth.revoke_evicted().await;
+
+            let token_hash = format!("{}_token", session_id);
+            remember.store.0.insert(&token_hash, "7", expires).await.unwrap();
+            auth.record_remember_token(token_hash);
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        // The evicted Sessions remember me token can not log it back in.
+        let now = Utc::now();
+        assert_eq!(remember.store.0.consume("a_token", now).await.unwrap(), None);
+        assert_eq!(remember.store.0.consume("b_token", now).await.unwrap(), Some("7".to_string()));
+
+        assert_eq!(session_value(&store, "a", DEFAULT_SESSION_KEY), None);
+        assert_eq!(session_value(&store, "b", DEFAULT_SESSION_KEY), Some("7".to_string()));
+        assert_eq!(session_value(&store, "c", DEFAULT_SESSION_KEY), Some("7".to_string()));
+        assert_eq!(session_value(&store, "other", DEFAULT_SESSION_KEY), Some("8".to_string()));
+    }
+
+    #[tokio::test]
+    async fn tenants_load_from_their_own_pool_and_cache() {
+        let store = store();
+        let mut pool = SQLxSessionAuthPool::<User>::new(None, None);
+        pool.tenant_pools.insert("a".to_string(), lazy_pool());
+        pool.tenant_pools.insert("b".to_string(), lazy_pool());
+        pool.user_cache_ttl = Some(Duration::from_secs(60));
+
+        let a = pool.tenant_pool(Some("a"), &store).unwrap();
+        let b = pool.tenant_pool(Some("b"), &store).unwrap();
+
+        assert!(std::ptr::eq(a, &pool.tenant_pools["a"]));
+        assert!(std::ptr::eq(b, &pool.tenant_pools["b"]));
+        assert!(pool.tenant_pool(Some("c"), &store).is_none());
+
+        pool.cache_user(Some("a"), &1, &user(1));
+
+        assert_eq!(pool.cached_user(Some("a"), &1), Some(user(1)));
+        assert_eq!(pool.cached_user(Some("b"), &1), None);
+        assert_eq!(pool.cached_user(None, &1), None);
+    }
+
+    #[tokio::test]
+    async fn strict_auth_fails_closed_on_database_errors() {
+        let pool = SQLxSessionAuthPool::<User>::new(None, None);
+
+        let (strict, _) = load_counted(&pool, 1, STRICT, timed_out).await;
+        let (lenient, _) = load_counted(&pool, 1, LoadOptions::default(), timed_out).await;
+
+        assert_eq!(strict, Err(Status::ServiceUnavailable));
+        assert_eq!(lenient, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn strict_auth_continues_without_missing_users() {
+        let pool = SQLxSessionAuthPool::<User>::new(None, None);
+
+        assert_eq!(load_counted(&pool, 1, STRICT, not_found).await.0, Ok(false));
+        assert_eq!(load_counted(&pool, 1, STRICT, || Ok(user(1))).await.0, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn anonymous_and_user_load_errors_are_handled_alike() {
+        let cases = [
+            (LoadErrorAction::Anonymous, Ok(false), 1),
+            (LoadErrorAction::Forbidden, Err(Status::Forbidden), 1),
+            (LoadErrorAction::Unavailable, Err(Status::ServiceUnavailable), 1),
+            (LoadErrorAction::Retry, Ok(false), 2),
+        ];
+
+        for (action, outcome, calls) in cases.iter().cloned() {
+            let mut pool = SQLxSessionAuthPool::<User>::new(None, Some(0));
+            pool.anonymous_refresh = Some(Duration::from_secs(60));
+            pool.classify_error = Some(Callback(Arc::new(move |_: &AuthError| action)));
+
+            let anonymous = load_counted(&pool, 0, LoadOptions::default(), timed_out).await;
+            let user = load_counted(&pool, 1, LoadOptions::default(), timed_out).await;
+
+            assert_eq!(anonymous, (outcome, calls), "{:?}", action);
+            assert_eq!(user, (outcome, calls), "{:?}", action);
+        }
+    }
+
+    #[tokio::test]
+    async fn fresh_auth_loads_past_a_warm_cache() {
+        let cache = PermissionCache::new(Duration::from_secs(60));
+        let mut pool = SQLxSessionAuthPool::<User>::new(None, None);
+        pool.user_cache_ttl = Some(Duration::from_secs(60));
+        pool.permission_cache = Some(cache.clone());
+        pool.cache_user(None, &1, &user(1));
+        assert!(cache.user(&user(1), None, "1").has("read", &None).await);
+
+        let fresh = LoadOptions {
+            fresh: true,
+            ..LoadOptions::default()
+        };
+        let reload = || {
+            Ok(User {
+                id: 1,
+                authenticated: false,
+            })
+        };
+
+        assert_eq!(load_counted(&pool, 1, LoadOptions::default(), reload).await, (Ok(true), 0));
+        assert_eq!(load_counted(&pool, 1, fresh, reload).await, (Ok(true), 1));
+        assert_eq!(pool.cached_user(None, &1).map(|user| user.authenticated), Some(false));
+
+        let reloaded = reload().unwrap();
+        assert!(!cache.user(&reloaded, None, "1").has("read", &None).await);
+    }
+
+    #[tokio::test]
+    async fn users_load_from_the_session_pool_without_a_client() {
+        let store = store();
+        let fairing = SqlxSessionAuthFairing::<User>::new_with_session_pool(None);
+        assert!(fairing.poll.is_none());
+
+        let pool = SQLxSessionAuthPool::<User>::new(fairing.poll.clone(), None);
+        assert!(std::ptr::eq(pool.connection_pool(&store).unwrap(), &store.client));
+        assert!(std::ptr::eq(pool.tenant_pool(None, &store).unwrap(), &store.client));
+
+        let pool = SQLxSessionAuthPool::<User>::new(Some(lazy_pool()), None);
+        let client = pool.client.as_ref().unwrap();
+        assert!(std::ptr::eq(pool.connection_pool(&store).unwrap(), client));
+    }
+
+    /// Holds the stores write lock on another thread while a request without a Session cookie is
+    /// resolved. Touching the store would block the request until the lock is released.
+    #[tokio::test]
+    async fn requests_without_a_session_never_lock_the_store() {
+        let store = store();
+        let pool = SQLxSessionAuthPool::<User>::new(None, None);
+        let client = Client::untracked(rocket::build().manage(store.clone()).manage(pool))
+            .await
+            .unwrap();
+        let request = client.get("/");
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let released = Arc::new(AtomicBool::new(false));
+        let holder = {
+            let (store, released) = (store.clone(), released.clone());
+
+            std::thread::spawn(move || {
+                let _guard = store.inner.write();
+                locked_tx.send(()).unwrap();
+                let _ = done_rx.recv_timeout(Duration::from_secs(1));
+                released.store(true, Ordering::SeqCst);
+            })
+        };
+
+        locked_rx.recv().unwrap();
+        let outcome = SQLxAuth::<User>::resolve(request.inner(), LoadOptions::default()).await;
+        let touched = released.load(Ordering::SeqCst);
+        let _ = done_tx.send(());
+        holder.join().unwrap();
+
+        assert!(!touched);
+
+        match outcome {
+            Outcome::Success(auth) => assert_eq!(auth.current_user_id(), None),
+            _ => panic!("the request without a Session failed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_anonymous_user_is_never_logged_in() {
+        let store = store();
+
+        let mut anonymous = SQLxAuth::detached(Some(0), Some(user(0)), store.clone());
+        anonymous.anonymous_user_id = Some(0);
+
+        let mut member = SQLxAuth::detached(Some(1), Some(user(1)), store);
+        member.anonymous_user_id = Some(0);
+
+        assert!(anonymous.is_authenticated());
+        assert!(!anonymous.is_logged_in());
+        assert!(member.is_logged_in());
+    }
+
+    #[tokio::test]
+    async fn logouts_end_the_role_preview() {
+        let store = store();
+        add_session(&store, "a");
+        add_session(&store, "b");
+
+        let a = auth(&store, "a");
+        a.login_user(1).unwrap();
+        a.preview_role("editor").unwrap();
+        a.logout_user().unwrap();
+
+        let b = auth(&store, "b");
+        b.login_user(1).unwrap();
+        b.preview_role("editor").unwrap();
+        b.logout_user_everywhere(&1).await.unwrap();
+
+        for session_id in &["a", "b"] {
+            assert_eq!(session_value(&store, session_id, DEFAULT_SESSION_KEY), None);
+            assert_eq!(session_value(&store, session_id, &a.login_at_key()), None);
+            assert_eq!(session_value(&store, session_id, &a.preview_role_key()), None);
+        }
     }
 }
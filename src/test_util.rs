@@ -0,0 +1,80 @@
+use crate::{session::Result, HasPermission, Rights, SQLxAuth, SQLxSessionAuth, SqlxDatabase};
+use rocket_sqlxsession::{SQLxSessionStore, SqlxSessionConfig};
+use serde::{Deserialize, Serialize};
+use sqlx::{pool::PoolConnection, postgres::PgPoolOptions};
+use std::collections::BTreeMap;
+
+/// The decisions the auth guard made for a request, for snapshot testing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthSnapshot {
+    /// The current id as json.
+    pub current_id: Option<serde_json::Value>,
+    pub is_authenticated: bool,
+    pub is_active: bool,
+    pub is_anonymous: bool,
+    /// The result of each named Rights given to snapshot_with_rights.
+    pub rights: BTreeMap<String, bool>,
+}
+
+impl<D> SQLxAuth<D>
+where
+    D: 'static + Clone + Sync + Send + SQLxSessionAuth<D>,
+{
+    /// Builds an auth for unit tests without a database or Rocket instance. The Session store
+    /// uses a lazy pool that never connects and holds no Session, so login_user and logout_user
+    /// return errors. Must be called within a tokio runtime.
+    pub fn for_test(current_user: Option<D>, current_id: Option<D::Id>) -> Result<Self> {
+        let client = PgPoolOptions::new().connect_lazy("postgres://localhost/test")?;
+        let session = SQLxSessionStore::new(client, SqlxSessionConfig::default());
+
+        Ok(SQLxAuth::detached(current_id, current_user, session))
+    }
+
+    /// Captures the current id and auth flags.
+    pub fn snapshot(&self) -> AuthSnapshot {
+        AuthSnapshot {
+            current_id: self
+                .current_id
+                .as_ref()
+                .and_then(|id| serde_json::to_value(id).ok()),
+            is_authenticated: self.is_authenticated(),
+            is_active: self.is_active(),
+            is_anonymous: self.is_anonymous(),
+            rights: BTreeMap::new(),
+        }
+    }
+
+    /// Captures the snapshot along with the result of evaluating each named Rights.
+    /// Rights always evaluate false when no user is loaded.
+    pub async fn snapshot_with_rights<P>(
+        &self,
+        rights: &[(&str, &Rights<P>)],
+        db: &Option<&mut PoolConnection<SqlxDatabase>>,
+    ) -> AuthSnapshot
+    where
+        D: HasPermission,
+        P: AsRef<str> + Clone + Send + Sync,
+    {
+        let mut snapshot = self.snapshot();
+
+        for (name, right) in rights {
+            let passed = match &self.current_user {
+                Some(user) => right.evaluate(user, db).await,
+                None => false,
+            };
+
+            snapshot.rights.insert(name.to_string(), passed);
+        }
+
+        snapshot
+    }
+}
+
+/// Evaluates the Rights against a user without a database connection, for testing HasPermission
+/// impls and Rights trees.
+pub async fn check_rights<P>(rights: &Rights<P>, user: &(dyn HasPermission + Sync)) -> bool
+where
+    P: AsRef<str> + Clone + Send + Sync,
+{
+    rights.evaluate(user, &None).await
+}
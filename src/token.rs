@@ -0,0 +1,131 @@
+use crate::session::Result;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Storage for single use tokens, like magic links or remember me tokens. Only the hash of a
+/// token is ever handed to the store.
+#[rocket::async_trait]
+pub trait TokenStore: Send + Sync {
+    /// Saves the tokens hash for the user until expires. The user id is serialized as json.
+    async fn insert(&self, token_hash: &str, user_id: &str, expires: DateTime<Utc>) -> Result;
+
+    /// Removes the token and returns its user id if it had not yet expired at now.
+    /// This must be atomic so a token can only ever be consumed once, for SQL stores
+    /// something like `DELETE FROM tokens WHERE hash = $1 RETURNING user_id, expires`.
+    async fn consume(&self, token_hash: &str, now: DateTime<Utc>) -> Result<Option<String>>;
+
+    /// Removes every token expired at now. Called whenever a new token is issued.
+    async fn clear_expired(&self, now: DateTime<Utc>) -> Result {
+        let _ = now;
+        Ok(())
+    }
+
+    /// Removes every token of the user, so none of them can log the user in anymore.
+    async fn remove_user(&self, user_id: &str) -> Result;
+}
+
+/// In memory TokenStore, Tokens are lost on restart so only use it for development or single
+/// process setups. Remembered users are forgotten by every restart.
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[rocket::async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn insert(&self, token_hash: &str, user_id: &str, expires: DateTime<Utc>) -> Result {
+        self.tokens
+            .lock()
+            .insert(token_hash.to_string(), (user_id.to_string(), expires));
+
+        Ok(())
+    }
+
+    async fn consume(&self, token_hash: &str, now: DateTime<Utc>) -> Result<Option<String>> {
+        Ok(match self.tokens.lock().remove(token_hash) {
+            Some((user_id, expires)) if expires > now => Some(user_id),
+            _ => None,
+        })
+    }
+
+    async fn clear_expired(&self, now: DateTime<Utc>) -> Result {
+        self.tokens.lock().retain(|_, (_, expires)| *expires > now);
+
+        Ok(())
+    }
+
+    async fn remove_user(&self, user_id: &str) -> Result {
+        self.tokens.lock().retain(|_, (id, _)| id != user_id);
+
+        Ok(())
+    }
+}
+
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn tokens_are_consumed_once() {
+        let store = MemoryTokenStore::new();
+        let now = Utc::now();
+
+        store.insert("hash", "1", now + Duration::minutes(5)).await.unwrap();
+
+        assert_eq!(store.consume("hash", now).await.unwrap(), Some("1".to_string()));
+        assert_eq!(store.consume("hash", now).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_not_consumed() {
+        let store = MemoryTokenStore::new();
+        let now = Utc::now();
+
+        store.insert("old", "1", now - Duration::minutes(1)).await.unwrap();
+        store.insert("new", "2", now + Duration::minutes(5)).await.unwrap();
+        store.clear_expired(now).await.unwrap();
+
+        assert_eq!(store.consume("old", now).await.unwrap(), None);
+        assert_eq!(store.consume("new", now).await.unwrap(), Some("2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn remove_user_revokes_only_their_tokens() {
+        let store = MemoryTokenStore::new();
+        let now = Utc::now();
+        let expires = now + Duration::minutes(5);
+
+        store.insert("a", "1", expires).await.unwrap();
+        store.insert("b", "1", expires).await.unwrap();
+        store.insert("c", "2", expires).await.unwrap();
+        store.remove_user("1").await.unwrap();
+
+        assert_eq!(store.consume("a", now).await.unwrap(), None);
+        assert_eq!(store.consume("b", now).await.unwrap(), None);
+        assert_eq!(store.consume("c", now).await.unwrap(), Some("2".to_string()));
+    }
+}